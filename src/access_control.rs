@@ -1,10 +1,22 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::RwLock;
 use casbin::prelude::*;
+use chrono::{DateTime, Utc};
 use lazy_static::lazy_static;
+use serde::Serialize;
 use futures::executor::block_on;
 use log::{debug, error};
+use crate::policy_writer::CasbinPolicy;
 
 const CONFIG: &str = "accessControl/policies.conf";
-const POLICY: &str = "accessControl/policies.csv";
+/// Policy types of the model
+pub const POLICY: &str = "p";
+pub const ROLE: &str = "g";
+pub const ENROLLMENT: &str = "g2";
+/// Effects of the `p` rules, a matching deny rule wins over the allow rules
+pub const ALLOW: &str = "allow";
+pub const DENY: &str = "deny";
 
 lazy_static! {
   pub static ref ACCESS_CTRL: AccessControl = {
@@ -21,24 +33,380 @@ lazy_static! {
   };
 }
 
+/// Attributes of a request, evaluated by the conditions of the policies.
+/// Times are in minutes since the Unix epoch, the policy engine only
+/// handles 32-bit integers.
+#[derive(Serialize, Hash, Debug, Clone, PartialEq)]
+pub struct RequestAttributes {
+  pub now: i32,
+  /// Grading deadline of the course of the object
+  pub deadline: i32,
+  /// Whether the grades of the course of the object are published
+  pub published: bool,
+}
+
+impl RequestAttributes {
+  /// Attributes of a request on an object without course, or on a course
+  /// without deadline and published
+  pub fn unrestricted(now: DateTime<Utc>) -> RequestAttributes {
+    RequestAttributes { now: unix_minutes(now), deadline: i32::MAX, published: true }
+  }
+}
+
+/// Minutes since the Unix epoch, dates after the year 6053 saturate so that
+/// a far deadline does not wrap into the past
+pub fn unix_minutes(time: DateTime<Utc>) -> i32 {
+  i32::try_from(time.timestamp() / 60).unwrap_or(i32::MAX)
+}
+
+/// Why a request is allowed or denied: the policy lines that matched and,
+/// for each of them, the role and resource group edges they went through.
+pub struct Explanation {
+  pub subject: String,
+  pub resource: String,
+  pub action: String,
+  pub allowed: bool,
+  pub matches: Vec<PolicyMatch>,
+  /// Roles of the subject and groups of the resource, shown when nothing
+  /// matched
+  pub roles: Vec<String>,
+  pub groups: Vec<String>,
+}
+
+pub struct PolicyMatch {
+  pub policy: Vec<String>,
+  pub edges: Vec<String>,
+}
+
+/// An action a user can or cannot do on an object, and the policy line
+/// granting or denying it, empty when no line matched
+pub struct Permission {
+  pub action: String,
+  pub resource: String,
+  pub allowed: bool,
+  pub policy: Vec<String>,
+}
+
+impl fmt::Display for Explanation {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let decision = if self.allowed { "allowed" } else { "denied" };
+    writeln!(f, "{} {} {}: {}", self.subject, self.action, self.resource, decision)?;
+    if self.matches.is_empty() {
+      writeln!(f, "  No policy matched.")?;
+      writeln!(f, "  Roles of {}: {}", self.subject, join_or_none(&self.roles))?;
+      writeln!(f, "  Groups of {}: {}", self.resource, join_or_none(&self.groups))?;
+    }
+    for m in self.matches.iter() {
+      writeln!(f, "  {}, {}", POLICY, m.policy.join(", "))?;
+      for edge in m.edges.iter() {
+        writeln!(f, "    via {}", edge)?;
+      }
+    }
+    Ok(())
+  }
+}
+
+fn join_or_none(values: &[String]) -> String {
+  if values.is_empty() {
+    "none".to_string()
+  } else {
+    values.join(", ")
+  }
+}
+
 pub struct AccessControl {
-  enforcer: Enforcer,
+  enforcer: RwLock<Enforcer>,
 }
 
 impl AccessControl {
+  /// The policies are kept in memory only, the enforcer denies everything
+  /// until they are loaded.
   pub async fn new() -> Result<AccessControl> {
-    let enforcer = Enforcer::new(CONFIG, POLICY).await?;
-    Ok(AccessControl { enforcer })
+    let mut enforcer = Enforcer::new(CONFIG, ()).await?;
+    enforcer.enable_auto_save(false);
+    Ok(AccessControl { enforcer: RwLock::new(enforcer) })
+  }
+
+  /// Replace all the policies of the enforcer
+  pub fn load(&self, policy: CasbinPolicy) -> Result<()> {
+    let mut enforcer = self.enforcer.write().unwrap();
+    block_on(enforcer.clear_policy())?;
+    for (ptype, rules) in policy.into_rules() {
+      add_rules(&mut enforcer, ptype, rules)?;
+    }
+    // Clearing the policies keeps the links of the former roles
+    enforcer.build_role_links()
+  }
+
+  /// Apply only the rules that differ between the enforcer and the given
+  /// policy. The role links are updated incrementally.
+  pub fn update(&self, policy: CasbinPolicy) -> Result<()> {
+    for (ptype, wanted) in policy.into_rules() {
+      let current: HashSet<Vec<String>> = self.rules(ptype).into_iter().collect();
+      let wanted: HashSet<Vec<String>> = wanted.into_iter().collect();
+      self.remove(ptype, current.difference(&wanted).cloned().collect())?;
+      self.add(ptype, wanted.difference(&current).cloned().collect())?;
+    }
+    Ok(())
+  }
+
+  pub fn add(&self, ptype: &str, rules: Vec<Vec<String>>) -> Result<()> {
+    add_rules(&mut self.enforcer.write().unwrap(), ptype, rules)
+  }
+
+  pub fn remove(&self, ptype: &str, rules: Vec<Vec<String>>) -> Result<()> {
+    remove_rules(&mut self.enforcer.write().unwrap(), ptype, rules)
+  }
+
+  /// Current rules of a policy type
+  pub fn rules(&self, ptype: &str) -> Vec<Vec<String>> {
+    get_rules(&self.enforcer.read().unwrap(), ptype)
   }
 
   /// Centralized access control mechanism
-  pub fn check_authorization(&self, subject: &str, resource: &str, action: &str) -> bool {
-    if let Ok(authorized) = self.enforcer.enforce((subject, resource, action)) {
+  pub fn check_authorization(&self, subject: &str, resource: &str, action: &str, attributes: &RequestAttributes) -> bool {
+    if let Ok(authorized) = self.enforcer.read().unwrap().enforce((subject, resource, action, attributes)) {
       authorized
     } else {
       error!("Casbin model does not map request.");
       false
     }
   }
+
+  /// Targets of the grouping rules of a policy type starting from a name
+  fn groups_of(&self, ptype: &str, name: &str) -> Vec<String> {
+    self.rules(ptype).into_iter()
+      .filter(|r| r[0] == name)
+      .map(|r| r[1].clone())
+      .collect()
+  }
+
+  /// Tell whether a request is allowed and which rules decided it
+  pub fn explain(&self, subject: &str, resource: &str, action: &str, attributes: &RequestAttributes) -> Result<Explanation> {
+    let (allowed, policies) = self.enforcer.read().unwrap().enforce_ex((subject, resource, action, attributes))?;
+    let roles = self.groups_of(ROLE, subject);
+    let groups = self.groups_of(ENROLLMENT, resource);
+    let matches = policies.into_iter()
+      .map(|policy| {
+        let mut edges = vec![];
+        if policy[0] != subject {
+          edges.push(format!("{}, {}, {}", ROLE, subject, policy[0]));
+        }
+        if policy[1] != resource {
+          if groups.contains(&policy[1]) {
+            edges.push(format!("{}, {}, {}", ENROLLMENT, resource, policy[1]));
+          } else {
+            edges.push(format!("keyMatch({}, {})", resource, policy[1]));
+          }
+        }
+        PolicyMatch { policy, edges }
+      })
+      .collect();
+    Ok(Explanation {
+      subject: subject.to_string(),
+      resource: resource.to_string(),
+      action: action.to_string(),
+      allowed,
+      matches,
+      roles,
+      groups,
+    })
+  }
+
+  /// Everything a user can do or is denied, the resource groups being
+  /// expanded to their members. Each (resource, action) pair is evaluated
+  /// with the attributes of its resource, so that conditions and deny rules
+  /// are taken into account.
+  pub fn permissions(&self, username: &str, attributes_of: impl Fn(&str) -> RequestAttributes) -> Vec<Permission> {
+    let roles = self.groups_of(ROLE, username);
+    let enrollments = self.rules(ENROLLMENT);
+    // Each (resource, action) pair with the deny lines covering it
+    let mut requests: Vec<(String, String, Vec<Vec<String>>)> = vec![];
+    for policy in self.rules(POLICY).into_iter().filter(|p| p[0] == username || roles.contains(&p[0])) {
+      let members = enrollments.iter().filter(|e| e[1] == policy[1]).map(|e| e[0].clone());
+      for resource in std::iter::once(policy[1].clone()).chain(members) {
+        let index = match requests.iter().position(|(r, a, _)| *r == resource && *a == policy[2]) {
+          Some(i) => i,
+          None => {
+            requests.push((resource, policy[2].clone(), vec![]));
+            requests.len() - 1
+          }
+        };
+        if policy[4] == DENY {
+          requests[index].2.push(policy.clone());
+        }
+      }
+    }
+    let enforcer = self.enforcer.read().unwrap();
+    let mut permissions = vec![];
+    for (resource, action, denials) in requests {
+      let attributes = attributes_of(&resource);
+      match enforcer.enforce_ex((username, resource.as_str(), action.as_str(), &attributes)) {
+        Ok((allowed, policies)) => {
+          // Casbin reports the matching allow line even when a deny line
+          // wins, the request is then denied by one of the deny lines
+          let policy = match policies.into_iter().next() {
+            Some(_) if !allowed => denials.into_iter().next().unwrap_or_default(),
+            Some(policy) => policy,
+            None => vec![],
+          };
+          permissions.push(Permission { action, resource, allowed, policy });
+        }
+        Err(e) => {
+          debug!("{}", e);
+          error!("Casbin model does not map request.");
+        }
+      }
+    }
+    permissions
+  }
+}
+
+fn is_grouping(ptype: &str) -> bool {
+  ptype.starts_with('g')
 }
 
+fn get_rules(enforcer: &Enforcer, ptype: &str) -> Vec<Vec<String>> {
+  if is_grouping(ptype) {
+    enforcer.get_named_grouping_policy(ptype)
+  } else {
+    enforcer.get_named_policy(ptype)
+  }
+}
+
+fn add_rules(enforcer: &mut Enforcer, ptype: &str, rules: Vec<Vec<String>>) -> Result<()> {
+  if rules.is_empty() {
+    return Ok(());
+  }
+  if is_grouping(ptype) {
+    block_on(enforcer.add_named_grouping_policies(ptype, rules))?;
+  } else {
+    block_on(enforcer.add_named_policies(ptype, rules))?;
+  }
+  Ok(())
+}
+
+fn remove_rules(enforcer: &mut Enforcer, ptype: &str, rules: Vec<Vec<String>>) -> Result<()> {
+  if rules.is_empty() {
+    return Ok(());
+  }
+  if is_grouping(ptype) {
+    block_on(enforcer.remove_named_grouping_policies(ptype, rules))?;
+  } else {
+    block_on(enforcer.remove_named_policies(ptype, rules))?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod test_access_control {
+  use super::*;
+
+  fn rule(fields: &[&str]) -> Vec<String> {
+    fields.iter().map(|f| f.to_string()).collect()
+  }
+
+  #[test]
+  fn update_must_add_and_remove_rules() {
+    let access_ctrl = block_on(AccessControl::new()).unwrap();
+    let attrs = RequestAttributes::unrestricted(Utc::now());
+    let policy = || CasbinPolicy {
+      policies: vec![rule(&["Prof", "courses/SLH", "Read", "true", "allow"])],
+      roles: vec![rule(&["prof1", "Prof"])],
+      enrollments: vec![rule(&["grades/alice/SLH", "courses/SLH"])],
+    };
+    access_ctrl.load(policy()).unwrap();
+    assert!(access_ctrl.check_authorization("prof1", "grades/alice/SLH", "Read", &attrs));
+    assert!(!access_ctrl.check_authorization("prof2", "grades/alice/SLH", "Read", &attrs));
+    access_ctrl.update(CasbinPolicy { roles: vec![rule(&["prof2", "Prof"])], ..policy() }).unwrap();
+    assert!(!access_ctrl.check_authorization("prof1", "grades/alice/SLH", "Read", &attrs));
+    assert!(access_ctrl.check_authorization("prof2", "grades/alice/SLH", "Read", &attrs));
+    access_ctrl.update(CasbinPolicy { roles: vec![rule(&["prof2", "Prof"])], enrollments: vec![], ..policy() }).unwrap();
+    assert!(!access_ctrl.check_authorization("prof2", "grades/alice/SLH", "Read", &attrs));
+    assert_eq!(access_ctrl.rules(ROLE), vec![rule(&["prof2", "Prof"])]);
+  }
+
+  #[test]
+  fn explanation_must_show_matched_policy_and_edges() {
+    let access_ctrl = block_on(AccessControl::new()).unwrap();
+    let attrs = RequestAttributes::unrestricted(Utc::now());
+    access_ctrl.load(CasbinPolicy {
+      policies: vec![rule(&["prof1", "courses/SLH", "Read", "true", "allow"]), rule(&["alice", "grades/alice/*", "Read", "r.attrs.published", "allow"])],
+      roles: vec![rule(&["prof1", "Prof"])],
+      enrollments: vec![rule(&["grades/alice/SLH", "courses/SLH"])],
+    }).unwrap();
+    let explanation = access_ctrl.explain("prof1", "grades/alice/SLH", "Read", &attrs).unwrap();
+    assert!(explanation.allowed);
+    assert_eq!(explanation.matches.len(), 1);
+    assert_eq!(explanation.matches[0].policy, rule(&["prof1", "courses/SLH", "Read", "true", "allow"]));
+    assert_eq!(explanation.matches[0].edges, vec!["g2, grades/alice/SLH, courses/SLH".to_string()]);
+    let explanation = access_ctrl.explain("prof1", "grades/alice/SLH", "Write", &attrs).unwrap();
+    assert!(!explanation.allowed);
+    assert!(explanation.matches.is_empty());
+    assert_eq!(explanation.roles, vec!["Prof".to_string()]);
+    let resources: Vec<String> = access_ctrl.permissions("prof1", |_| attrs.clone()).into_iter().map(|p| p.resource).collect();
+    assert_eq!(resources, vec!["courses/SLH".to_string(), "grades/alice/SLH".to_string()]);
+  }
+
+  #[test]
+  fn conditions_must_use_request_attributes() {
+    let access_ctrl = block_on(AccessControl::new()).unwrap();
+    access_ctrl.load(CasbinPolicy {
+      policies: vec![
+        rule(&["prof1", "courses/SLH", "Write", "r.attrs.now <= r.attrs.deadline", "allow"]),
+        rule(&["alice", "grades/alice/*", "Read", "r.attrs.published", "allow"]),
+      ],
+      roles: vec![],
+      enrollments: vec![rule(&["grades/alice/SLH", "courses/SLH"])],
+    }).unwrap();
+    let now = Utc::now();
+    let open = RequestAttributes { deadline: unix_minutes(now) + 60, published: false, ..RequestAttributes::unrestricted(now) };
+    let closed = RequestAttributes { deadline: unix_minutes(now) - 60, published: true, ..open.clone() };
+    assert!(access_ctrl.check_authorization("prof1", "grades/alice/SLH", "Write", &open));
+    assert!(!access_ctrl.check_authorization("prof1", "grades/alice/SLH", "Write", &closed));
+    assert!(!access_ctrl.check_authorization("alice", "grades/alice/SLH", "Read", &open));
+    assert!(access_ctrl.check_authorization("alice", "grades/alice/SLH", "Read", &closed));
+  }
+
+  #[test]
+  fn deny_must_win_until_it_expires() {
+    let access_ctrl = block_on(AccessControl::new()).unwrap();
+    let now = Utc::now();
+    let until = format!("r.attrs.now < {}", unix_minutes(now) + 60);
+    access_ctrl.load(CasbinPolicy {
+      policies: vec![
+        rule(&["Prof", "courses/SLH", "Read", "true", "allow"]),
+        rule(&["prof1", "courses/SLH", "Read", &until, "deny"]),
+        rule(&["prof2", "courses/SLH", "Write", &until, "allow"]),
+      ],
+      roles: vec![rule(&["prof1", "Prof"]), rule(&["prof2", "Prof"])],
+      enrollments: vec![rule(&["grades/alice/SLH", "courses/SLH"])],
+    }).unwrap();
+    let attrs = RequestAttributes::unrestricted(now);
+    let later = RequestAttributes::unrestricted(now + chrono::Duration::hours(2));
+    assert!(!access_ctrl.check_authorization("prof1", "grades/alice/SLH", "Read", &attrs));
+    assert!(access_ctrl.check_authorization("prof1", "grades/alice/SLH", "Read", &later));
+    assert!(access_ctrl.check_authorization("prof2", "grades/alice/SLH", "Read", &attrs));
+    assert!(access_ctrl.check_authorization("prof2", "grades/alice/SLH", "Write", &attrs));
+    assert!(!access_ctrl.check_authorization("prof2", "grades/alice/SLH", "Write", &later));
+    let denied: Vec<Permission> = access_ctrl.permissions("prof1", |_| attrs.clone()).into_iter().filter(|p| !p.allowed).collect();
+    assert_eq!(denied.len(), 2);
+    assert!(denied.iter().all(|p| p.policy[4] == DENY));
+    assert!(access_ctrl.permissions("prof1", |_| later.clone()).iter().all(|p| p.allowed));
+    // The write delegated until the end of the hour is reported as denied
+    // once expired
+    let writes = |attrs: &RequestAttributes| access_ctrl.permissions("prof2", |_| attrs.clone()).into_iter()
+      .filter(|p| p.action == "Write")
+      .map(|p| p.allowed)
+      .collect::<Vec<bool>>();
+    assert_eq!(writes(&attrs), vec![true, true]);
+    assert_eq!(writes(&later), vec![false, false]);
+  }
+
+  #[test]
+  fn far_dates_must_not_wrap() {
+    let far = DateTime::parse_from_rfc3339("9999-12-31T00:00:00Z").unwrap().with_timezone(&Utc);
+    assert_eq!(unix_minutes(far), i32::MAX);
+    assert!(unix_minutes(Utc::now()) > 0);
+  }
+}
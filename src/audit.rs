@@ -0,0 +1,311 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use log::{debug, error};
+use once_cell::sync::Lazy;
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use crate::config::APP_CONFIG;
+use crate::keystore;
+use crate::persistence::write_atomically;
+
+/// prev_hash of the first entry
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+/// Context of the MAC key derived from a master key
+const MAC_KEY_CONTEXT: &[u8] = b"KING audit log";
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum Outcome {
+  Success,
+  Failure,
+  Denied,
+}
+
+/// What happened, hashed together with the hash of the previous entry
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct AuditEvent {
+  seq: u64,
+  timestamp: DateTime<Utc>,
+  actor: String,
+  action: String,
+  target: String,
+  outcome: Outcome,
+  prev_hash: String,
+}
+
+/// A line of the audit log
+#[derive(Serialize, Deserialize, Debug)]
+struct AuditEntry {
+  #[serde(flatten)]
+  event: AuditEvent,
+  hash: String,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  mac: Option<EntryMac>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct EntryMac {
+  key_id: u32,
+  value: String,
+}
+
+/// Last entry of the log, stored apart so that removing entries at the end
+/// of the log is detected too.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ChainHead {
+  seq: u64,
+  hash: String,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  mac: Option<EntryMac>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AuditError {
+  Unreadable(u64),
+  BrokenChain(u64),
+  BadHash(u64),
+  BadMac(u64),
+  MissingMac(u64),
+  HeadMismatch,
+}
+
+impl fmt::Display for AuditError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      AuditError::Unreadable(line) => write!(f, "Line {} of the audit log cannot be read", line),
+      AuditError::BrokenChain(seq) => write!(f, "Entry {} does not follow the previous entry, entries were removed or reordered", seq),
+      AuditError::BadHash(seq) => write!(f, "Entry {} was modified", seq),
+      AuditError::BadMac(seq) => write!(f, "Entry {} has an invalid MAC", seq),
+      AuditError::MissingMac(seq) => write!(f, "Entry {} has no MAC", seq),
+      AuditError::HeadMismatch => write!(f, "The audit log does not end with the last recorded entry, entries were removed"),
+    }
+  }
+}
+
+impl Error for AuditError {}
+
+/// Sequence number and hash of the last entry, read from the log once
+static CHAIN: Lazy<Mutex<Option<(u64, String)>>> = Lazy::new(|| Mutex::new(None));
+
+/// File holding the last entry of the log at path
+pub fn head_path(path: &str) -> String {
+  format!("{}.head", path)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+  bytes.iter().fold(String::new(), |mut out, b| {
+    let _ = write!(out, "{:02x}", b);
+    out
+  })
+}
+
+fn hash_event(event: &AuditEvent) -> Result<String, Box<dyn Error>> {
+  let serialized = serde_json::to_vec(event)?;
+  Ok(to_hex(&Sha256::digest(serialized)))
+}
+
+fn compute_mac(key_id: u32, data: &str) -> Result<String, Box<dyn Error>> {
+  let mut mac = Hmac::<Sha256>::new_from_slice(&keystore::derived_key(key_id, MAC_KEY_CONTEXT)?)?;
+  mac.update(data.as_bytes());
+  Ok(to_hex(&mac.finalize().into_bytes()))
+}
+
+fn mac_entry(data: &str) -> Result<Option<EntryMac>, Box<dyn Error>> {
+  if !APP_CONFIG.audit_mac {
+    return Ok(None);
+  }
+  let (key_id, _) = keystore::current_key()?;
+  Ok(Some(EntryMac { key_id, value: compute_mac(key_id, data)? }))
+}
+
+fn check_mac(mac: &Option<EntryMac>, data: &str, seq: u64) -> Result<(), Box<dyn Error>> {
+  match mac {
+    Some(mac) if compute_mac(mac.key_id, data)? != mac.value => Err(AuditError::BadMac(seq).into()),
+    None if APP_CONFIG.audit_mac => Err(AuditError::MissingMac(seq).into()),
+    _ => Ok(()),
+  }
+}
+
+fn read_entries(path: &str) -> Result<Vec<AuditEntry>, Box<dyn Error>> {
+  if !Path::new(path).exists() {
+    return Ok(vec![]);
+  }
+  let mut entries = vec![];
+  for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+    let entry = serde_json::from_str(&line?).map_err(|_| AuditError::Unreadable(i as u64 + 1))?;
+    entries.push(entry);
+  }
+  Ok(entries)
+}
+
+/// Write the entry following the last one to the log and update the head,
+/// return the new last entry.
+fn append_entry(log: &str, head: &str, last: (u64, String), actor: &str, action: &str, target: &str, outcome: Outcome) -> Result<(u64, String), Box<dyn Error>> {
+  let (last_seq, last_hash) = last;
+  let event = AuditEvent {
+    seq: last_seq + 1,
+    timestamp: Utc::now(),
+    actor: actor.to_string(),
+    action: action.to_string(),
+    target: target.to_string(),
+    outcome,
+    prev_hash: last_hash,
+  };
+  let hash = hash_event(&event)?;
+  let entry = AuditEntry { mac: mac_entry(&hash)?, event, hash };
+  let mut file = OpenOptions::new().append(true).create(true).open(log)?;
+  writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+  file.sync_data()?;
+  let chain_head = ChainHead {
+    seq: entry.event.seq,
+    mac: mac_entry(&format!("{}:{}", entry.event.seq, entry.hash))?,
+    hash: entry.hash.clone(),
+  };
+  write_atomically(head, serde_json::to_string(&chain_head)?.as_bytes(), false)?;
+  Ok((entry.event.seq, entry.hash))
+}
+
+fn append(actor: &str, action: &str, target: &str, outcome: Outcome) -> Result<(), Box<dyn Error>> {
+  let path = APP_CONFIG.audit_log.as_str();
+  let mut chain = CHAIN.lock().unwrap();
+  if chain.is_none() {
+    *chain = Some(match read_entries(path)?.last() {
+      Some(last) => (last.event.seq, last.hash.clone()),
+      None => (0, GENESIS_HASH.to_string()),
+    });
+  }
+  let last = append_entry(path, &head_path(path), chain.clone().unwrap(), actor, action, target, outcome)?;
+  *chain = Some(last);
+  Ok(())
+}
+
+/// Append an event to the audit log. A failure to write is reported but
+/// does not stop the program.
+pub fn record(actor: &str, action: &str, target: &str, outcome: Outcome) {
+  if let Err(e) = append(actor, action, target, outcome) {
+    debug!("{}", e);
+    error!("Cannot write the audit log, event {} of {} on {} lost.", action, actor, target);
+  }
+}
+
+/// Check the whole chain of the log and its head, return the number of entries
+pub fn verify(log: &str, head: &str) -> Result<u64, Box<dyn Error>> {
+  let mut prev = (0, GENESIS_HASH.to_string());
+  for entry in read_entries(log)? {
+    let seq = entry.event.seq;
+    if seq != prev.0 + 1 || entry.event.prev_hash != prev.1 {
+      return Err(AuditError::BrokenChain(seq).into());
+    }
+    if hash_event(&entry.event)? != entry.hash {
+      return Err(AuditError::BadHash(seq).into());
+    }
+    check_mac(&entry.mac, &entry.hash, seq)?;
+    prev = (seq, entry.hash);
+  }
+  if Path::new(head).exists() {
+    let head: ChainHead = serde_json::from_reader(BufReader::new(File::open(head)?))?;
+    check_mac(&head.mac, &format!("{}:{}", head.seq, head.hash), head.seq)?;
+    if head.seq != prev.0 || head.hash != prev.1 {
+      return Err(AuditError::HeadMismatch.into());
+    }
+  } else if prev.0 > 0 {
+    return Err(AuditError::HeadMismatch.into());
+  }
+  Ok(prev.0)
+}
+
+#[cfg(test)]
+mod test_audit {
+  use super::*;
+
+  #[test]
+  fn hash_must_cover_every_field() {
+    let event = AuditEvent {
+      seq: 1,
+      timestamp: Utc::now(),
+      actor: "prof1".to_string(),
+      action: "grade.add".to_string(),
+      target: "alice".to_string(),
+      outcome: Outcome::Success,
+      prev_hash: GENESIS_HASH.to_string(),
+    };
+    let hash = hash_event(&event).unwrap();
+    assert_eq!(hash.len(), 64);
+    let edited = AuditEvent { outcome: Outcome::Denied, ..event.clone() };
+    assert_ne!(hash_event(&edited).unwrap(), hash);
+    let moved = AuditEvent { prev_hash: hash.clone(), ..event };
+    assert_ne!(hash_event(&moved).unwrap(), hash);
+  }
+
+  /// Write a log of three entries, return the paths of the log and its head,
+  /// and the head as it was after the second entry
+  fn sample_log(name: &str) -> (String, String, String) {
+    let dir = std::env::temp_dir().join(format!("king-audit-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let log = dir.join("audit.log").to_string_lossy().into_owned();
+    let head = head_path(&log);
+    let _ = std::fs::remove_file(&log);
+    let mut last = (0, GENESIS_HASH.to_string());
+    let mut second_head = String::new();
+    for target in ["alice", "bob", "carol"] {
+      last = append_entry(&log, &head, last, "prof1", "grade.add", target, Outcome::Success).unwrap();
+      if last.0 == 2 {
+        second_head = std::fs::read_to_string(&head).unwrap();
+      }
+    }
+    assert_eq!(verify(&log, &head).unwrap(), 3);
+    (log, head, second_head)
+  }
+
+  fn rewrite_lines(log: &str, edit: impl Fn(Vec<String>) -> Vec<String>) {
+    let lines = std::fs::read_to_string(log).unwrap().lines().map(String::from).collect();
+    std::fs::write(log, edit(lines).join("\n") + "\n").unwrap();
+  }
+
+  fn audit_error(log: &str, head: &str) -> AuditError {
+    match verify(log, head).unwrap_err().downcast::<AuditError>() {
+      Ok(e) => *e,
+      Err(e) => panic!("unexpected error {}", e),
+    }
+  }
+
+  #[test]
+  fn removed_middle_entry_must_be_detected() {
+    let (log, head, _) = sample_log("middle");
+    rewrite_lines(&log, |mut lines| {
+      lines.remove(1);
+      lines
+    });
+    assert_eq!(audit_error(&log, &head), AuditError::BrokenChain(3));
+  }
+
+  #[test]
+  fn edited_entry_must_be_detected() {
+    let (log, head, _) = sample_log("edit");
+    rewrite_lines(&log, |lines| lines.into_iter().map(|l| l.replace("\"bob\"", "\"eve\"")).collect());
+    assert_eq!(audit_error(&log, &head), AuditError::BadHash(2));
+  }
+
+  #[test]
+  fn truncated_log_must_be_detected() {
+    let (log, head, _) = sample_log("truncate");
+    rewrite_lines(&log, |mut lines| {
+      lines.pop();
+      lines
+    });
+    assert_eq!(audit_error(&log, &head), AuditError::HeadMismatch);
+  }
+
+  #[test]
+  fn stale_head_must_be_detected() {
+    let (log, head, second_head) = sample_log("head");
+    std::fs::write(&head, second_head).unwrap();
+    assert_eq!(audit_error(&log, &head), AuditError::HeadMismatch);
+  }
+}
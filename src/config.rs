@@ -0,0 +1,188 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use log::{debug, info, warn};
+use once_cell::sync::Lazy;
+use serde::{Serialize, Deserialize};
+
+const CONFIG_FILE: &str = "config.json";
+
+/// Precision used to round averages, Swiss grades go from 1 to 6 and are
+/// rounded either to the half or to the tenth of a point.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum Rounding {
+  Half,
+  Tenth,
+}
+
+/// Where the data key comes from: a key file next to the data, or a key
+/// wrapped with a key derived from the administrator passphrase.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum KeyProtection {
+  File,
+  Passphrase,
+}
+
+/// Backend persisting the databases
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum StorageBackend {
+  Json,
+  Sqlite,
+}
+
+/// Rules a new password must follow
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PasswordPolicy {
+  pub min_length: usize,
+  pub require_lowercase: bool,
+  pub require_uppercase: bool,
+  pub require_digit: bool,
+  pub require_symbol: bool,
+  /// Days before a password expires, never if None
+  pub max_age_days: Option<i64>,
+}
+
+impl Default for PasswordPolicy {
+  fn default() -> Self {
+    PasswordPolicy {
+      min_length: 10,
+      require_lowercase: true,
+      require_uppercase: true,
+      require_digit: true,
+      require_symbol: false,
+      max_age_days: Some(180),
+    }
+  }
+}
+
+/// Limits on failed login attempts
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LoginPolicy {
+  /// Failures before an account is locked
+  pub max_failures: u32,
+  pub lockout_minutes: i64,
+  pub backoff_base_ms: u64,
+  pub backoff_max_ms: u64,
+  /// Failures of all users within the window before every login slows down
+  pub global_max_failures: u32,
+  pub global_window_minutes: i64,
+}
+
+impl Default for LoginPolicy {
+  fn default() -> Self {
+    LoginPolicy {
+      max_failures: 5,
+      lockout_minutes: 15,
+      backoff_base_ms: 500,
+      backoff_max_ms: 30_000,
+      global_max_failures: 20,
+      global_window_minutes: 10,
+    }
+  }
+}
+
+/// Argon2 cost of the password hashes. Stored hashes made with lower
+/// costs are upgraded on the next successful login.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct HashingParams {
+  pub memory_kib: u32,
+  pub iterations: u32,
+  pub parallelism: u32,
+}
+
+impl Default for HashingParams {
+  fn default() -> Self {
+    HashingParams {
+      memory_kib: 19456,
+      iterations: 2,
+      parallelism: 1,
+    }
+  }
+}
+
+/// Settings read from `config.json`. Missing fields take their default value.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AppConfig {
+  pub grade_rounding: Rounding,
+  pub key_protection: KeyProtection,
+  pub storage: StorageBackend,
+  pub sqlite_path: String,
+  /// Directory of the data keys, kept apart from the databases
+  pub key_dir: String,
+  pub password_policy: PasswordPolicy,
+  pub login_policy: LoginPolicy,
+  pub password_hashing: HashingParams,
+  /// Pepper file, kept apart from the databases. No pepper if None.
+  pub pepper_file: Option<String>,
+  /// Inactivity after which a user is logged out
+  pub session_idle_minutes: i64,
+  pub audit_log: String,
+  /// Authenticate the audit entries with a key derived from the master key
+  pub audit_mac: bool,
+}
+
+impl Default for AppConfig {
+  fn default() -> Self {
+    AppConfig {
+      grade_rounding: Rounding::Half,
+      key_protection: KeyProtection::File,
+      storage: StorageBackend::Json,
+      sqlite_path: "db/king.sqlite3".to_string(),
+      key_dir: "secret".to_string(),
+      password_policy: PasswordPolicy::default(),
+      login_policy: LoginPolicy::default(),
+      password_hashing: HashingParams::default(),
+      pepper_file: None,
+      session_idle_minutes: 10,
+      audit_log: "db/audit.log".to_string(),
+      audit_mac: true,
+    }
+  }
+}
+
+impl AppConfig {
+  /// Configuration of the tests, which must not write next to the sources:
+  /// the databases are kept in memory, the keys and the audit log in a
+  /// temporary directory.
+  fn for_tests() -> AppConfig {
+    let dir = std::env::temp_dir().join(format!("king-test-{}", std::process::id()));
+    if let Err(e) = std::fs::create_dir_all(&dir) {
+      warn!("Cannot create the test directory : {}", e);
+    }
+    AppConfig {
+      storage: StorageBackend::Sqlite,
+      sqlite_path: ":memory:".to_string(),
+      key_dir: dir.to_string_lossy().into_owned(),
+      audit_log: dir.join("audit.log").to_string_lossy().into_owned(),
+      ..AppConfig::default()
+    }
+  }
+}
+
+pub static APP_CONFIG: Lazy<AppConfig> = Lazy::new(|| {
+  if cfg!(test) {
+    return AppConfig::for_tests();
+  }
+  match read_config(CONFIG_FILE) {
+    Ok(config) => config,
+    Err(e) => {
+      debug!("{}", e);
+      info!("No usable configuration file, using defaults.");
+      AppConfig::default()
+    }
+  }
+});
+
+fn read_config(path: &str) -> Result<AppConfig, Box<dyn Error>> {
+  let file = File::open(path)?;
+  let reader = BufReader::new(file);
+  let config = serde_json::from_reader(reader).map_err(|e| {
+    warn!("Invalid configuration file : {}", e);
+    e
+  })?;
+  Ok(config)
+}
@@ -0,0 +1,115 @@
+use std::error::Error;
+use std::fmt;
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Course {
+  pub id: String,
+  pub title: String,
+  pub teachers: Vec<String>,
+  pub students: Vec<String>,
+  /// Teachers can no longer change the grades after this date
+  #[serde(default)]
+  pub grading_deadline: Option<DateTime<Utc>>,
+  /// Students can read their grades once they are published
+  #[serde(default)]
+  pub published: bool,
+  #[serde(default)]
+  pub suspensions: Vec<Suspension>,
+  #[serde(default)]
+  pub delegations: Vec<Delegation>,
+}
+
+/// A teacher denied any access to a course until a date
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Suspension {
+  pub teacher: String,
+  pub until: DateTime<Utc>,
+}
+
+/// Rights of a teacher on a course lent to an assistant until a date
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Delegation {
+  pub delegator: String,
+  pub delegate: String,
+  pub until: DateTime<Utc>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CourseError {
+  Unauthorized,
+  UnknownCourse,
+  UnknownUser,
+  /// Rights can only be delegated to another teacher who is not a student
+  /// of the course
+  InvalidDelegate,
+  Policy,
+}
+
+impl fmt::Display for CourseError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      CourseError::Unauthorized => write!(f, "Unauthorized"),
+      CourseError::UnknownCourse => write!(f, "Unknown course"),
+      CourseError::UnknownUser => write!(f, "Unknown user"),
+      CourseError::InvalidDelegate => write!(f, "Rights can only be delegated to another teacher not enrolled in the course"),
+      CourseError::Policy => write!(f, "The access control policies could not be updated"),
+    }
+  }
+}
+
+impl Error for CourseError {}
+
+impl Course {
+  /// Course without deadline, suspension nor delegation, whose grades are
+  /// not published yet
+  pub fn new(id: &str, title: &str, teachers: &[&str], students: &[&str]) -> Course {
+    Course {
+      id: id.to_string(),
+      title: title.to_string(),
+      teachers: teachers.iter().map(|t| t.to_string()).collect(),
+      students: students.iter().map(|s| s.to_string()).collect(),
+      grading_deadline: None,
+      published: false,
+      suspensions: vec![],
+      delegations: vec![],
+    }
+  }
+
+  pub fn is_taught_by(&self, username: &str) -> bool {
+    self.teachers.iter().any(|t| t == username)
+  }
+
+  pub fn has_student(&self, username: &str) -> bool {
+    self.students.iter().any(|s| s == username)
+  }
+
+  pub fn is_delegated_to(&self, username: &str, now: DateTime<Utc>) -> bool {
+    self.delegations.iter().any(|d| d.delegate == username && now < d.until)
+  }
+
+  /// Forget the suspensions and delegations that are over
+  pub fn prune_expired(&mut self, now: DateTime<Utc>) {
+    self.suspensions.retain(|s| now < s.until);
+    self.delegations.retain(|d| now < d.until);
+  }
+}
+
+/// Casbin object protecting the grades of a student in a course
+pub fn grades_resource(student_name: &str, course_id: &str) -> String {
+  format!("grades/{}/{}", student_name, course_id)
+}
+
+/// Casbin object gathering the grades of all students enrolled in a course
+pub fn course_resource(course_id: &str) -> String {
+  format!("courses/{}", course_id)
+}
+
+/// Course of a grades or course object, None for the other objects
+pub fn course_of_resource(resource: &str) -> Option<&str> {
+  match resource.split('/').collect::<Vec<&str>>()[..] {
+    ["grades", _, course_id] | ["courses", course_id] => Some(course_id),
+    _ => None,
+  }
+}
@@ -1,30 +1,62 @@
 use std::collections::HashMap;
 use std::error::Error;
-use std::fs::File;
-use std::io::{BufReader, BufWriter, Read, Write};
-use std::path::Path;
 use std::sync::Mutex;
 use lazy_static::{__Deref, lazy_static};
+use chrono::{DateTime, Utc};
 use log::{debug, error, info, trace, warn};
-use crate::access_control::{ACCESS_CTRL};
-use crate::encryption::{create_encryption_key, create_nonce, decrypt_to_string, encrypt_string, read_b64_from_file, vec_to_key, vec_to_nonce};
+use crate::access_control::{ACCESS_CTRL, RequestAttributes, unix_minutes};
+use crate::audit;
+use crate::audit::Outcome;
+use crate::course::{Course, CourseError, Delegation, Suspension, course_of_resource, course_resource, grades_resource};
+use crate::grade::{Grade, GradeError, LEGACY_COURSE_ID, next_grade_id};
+use crate::keystore;
+use crate::login_throttle::LoginThrottle;
+use crate::policy_writer::CasbinPolicy;
+use crate::storage::{open_storage, Storage};
 
-use crate::user::{Action, User};
+use crate::user::{Action, Role, User};
 
-const DATABASE_FILE: &str = "db/grades_db.json";
-const USERS_DATABASE_FILE: &str = "db/usr_db.json";
+/// Actor of the audit events of the command line administration commands
+pub const CLI_ACTOR: &str = "cli";
 
 
 lazy_static! {
-    pub static ref GRADE_DATABASE: Mutex<HashMap<String, Vec<f32>>> = {
+    /// Backend persisting the databases below, which are kept in memory
+    static ref STORAGE: Mutex<Box<dyn Storage>> = {
+      Mutex::new(load_or_exit(open_storage(), "storage"))
+    };
+    static ref GRADE_DATABASE: Mutex<HashMap<String, Vec<Grade>>> = {
 
-      let map = read_grades_db(DATABASE_FILE).unwrap_or(HashMap::new());
+      let map = load_or_exit(STORAGE.lock().unwrap().load_grades(), "grades");
       Mutex::new(map)
     };
     pub static ref USERS_DATABASE: Mutex<HashMap<String, User>> = {
-        let map = read_usr_db(USERS_DATABASE_FILE).unwrap_or(HashMap::new());
+        let map = load_or_exit(STORAGE.lock().unwrap().load_users(), "users");
+        Mutex::new(map)
+    };
+    pub static ref COURSES_DATABASE: Mutex<HashMap<String, Course>> = {
+        let map = load_or_exit(STORAGE.lock().unwrap().load_courses(), "courses");
         Mutex::new(map)
     };
+    /// Failed logins of all the users
+    pub static ref LOGIN_THROTTLE: Mutex<LoginThrottle> = {
+        let throttle = load_or_exit(STORAGE.lock().unwrap().load_login_throttle(), "login throttle");
+        Mutex::new(throttle)
+    };
+}
+
+/// A database that exists but cannot be read must not be replaced by an
+/// empty one on the next save, so we stop there.
+fn load_or_exit<T>(loaded: Result<T, Box<dyn Error>>, name: &str) -> T {
+  match loaded {
+    Ok(val) => val,
+    Err(e) => {
+      debug!("{}", e);
+      error!("Cannot load the {} database.", name);
+      println!("Unexpected end of program.");
+      std::process::exit(1);
+    }
+  }
 }
 
 pub fn save_db() -> Result<(), Box<dyn Error>> {
@@ -32,27 +64,139 @@ pub fn save_db() -> Result<(), Box<dyn Error>> {
     // Declare the value to instantiate the lazy variable in case of quitting
     // directly after start
     let value = GRADE_DATABASE.lock().unwrap();
-    let mut file = File::create(DATABASE_FILE)?;
-    let db_str = serde_json::to_string(value.deref())?;
-    let key = match read_b64_from_file("secret/key.txt") {
-      Ok(val) => vec_to_key(val).unwrap(),
-      Err(_) => create_encryption_key("secret/key.txt"),
-    };
-    let nonce = create_nonce("secret/nonce.txt");
-    let cipher = encrypt_string(&db_str, &key, &nonce)?;
-    file.write(&cipher)?;
+    STORAGE.lock().unwrap().save_grades(value.deref())?;
   }
 
+  save_users()?;
+
   {
-    let value = USERS_DATABASE.deref();
-    let file = File::create(USERS_DATABASE_FILE)?;
-    let writer = BufWriter::new(file);
-    serde_json::to_writer(writer, value)?;
+    let value = COURSES_DATABASE.lock().unwrap();
+    STORAGE.lock().unwrap().save_courses(value.deref())?;
   }
   trace!("Database successfully saved.");
   Ok(())
 }
 
+/// Save the users with the login counters, which must be persisted even
+/// when the login fails.
+pub fn save_users() -> Result<(), Box<dyn Error>> {
+  {
+    let value = USERS_DATABASE.lock().unwrap();
+    STORAGE.lock().unwrap().save_users(value.deref())?;
+  }
+
+  {
+    let value = LOGIN_THROTTLE.lock().unwrap();
+    STORAGE.lock().unwrap().save_login_throttle(value.deref())?;
+  }
+  Ok(())
+}
+
+/// Check that every database can be read with the current keys, so that a
+/// failure aborts a key change before anything is overwritten.
+fn check_readable() -> Result<(), Box<dyn Error>> {
+  let storage = STORAGE.lock().unwrap();
+  storage.load_grades()?;
+  storage.load_users()?;
+  storage.load_courses()?;
+  Ok(())
+}
+
+/// Re-encrypt all the databases with a new data key.
+pub fn rotate_key() -> Result<u32, Box<dyn Error>> {
+  check_readable()?;
+  let version = keystore::rotate()?;
+  keystore::rewrap_record_keys()?;
+  save_db()?;
+  info!("All databases re-encrypted with key version {}.", version);
+  audit::record(CLI_ACTOR, "key.rotate", &version.to_string(), Outcome::Success);
+  Ok(version)
+}
+
+/// Encrypt the grades of a student with a new record key, then forget the
+/// previous ones.
+pub fn rekey_student(student_name: &str) -> Result<u32, Box<dyn Error>> {
+  check_readable()?;
+  let version = {
+    let grades = GRADE_DATABASE.lock().unwrap();
+    rekey_records(STORAGE.lock().unwrap().as_mut(), grades.deref(), student_name)?
+  };
+  info!("Grades of {} re-encrypted with record key version {}.", student_name, version);
+  audit::record(CLI_ACTOR, "record_key.rotate", student_name, Outcome::Success);
+  Ok(version)
+}
+
+/// The grades are saved twice so that the backup generation is sealed with
+/// the new key too before the previous keys are forgotten.
+fn rekey_records(storage: &mut dyn Storage, grades: &HashMap<String, Vec<Grade>>, student_name: &str) -> Result<u32, Box<dyn Error>> {
+  let version = keystore::rotate_record_key(student_name)?;
+  storage.save_grades(grades)?;
+  storage.save_grades(grades)?;
+  keystore::prune_record_keys(student_name)?;
+  Ok(version)
+}
+
+/// Delete the grades of a student and destroy their record keys, so that
+/// the grades cannot be recovered from a backup either (GDPR erasure).
+/// Only the grades are saved, this is also done when a user is deleted
+/// while the users and the courses are locked.
+pub fn erase_student_grades(actor: &str, student_name: &str) -> Result<(), Box<dyn Error>> {
+  {
+    let mut grades = GRADE_DATABASE.lock().unwrap();
+    grades.remove(student_name);
+    STORAGE.lock().unwrap().save_grades(grades.deref())?;
+  }
+  keystore::shred_record_keys(student_name)?;
+  info!("Grades of {} erased.", student_name);
+  audit::record(actor, "grades.erase", student_name, Outcome::Success);
+  Ok(())
+}
+
+/// Apply a change to the users and the courses, then push the resulting
+/// policy changes to the access control so that it takes effect at once.
+/// If the policies cannot be updated, the change is rolled back and the
+/// whole policy is reloaded, since the update may have been applied only
+/// in part.
+pub fn update_users_and_courses<T, F>(change: F) -> Result<T, Box<dyn Error>>
+  where F: FnOnce(&mut HashMap<String, User>, &mut HashMap<String, Course>) -> T {
+  let mut usr_db = USERS_DATABASE.deref().lock().unwrap();
+  let mut course_db = COURSES_DATABASE.deref().lock().unwrap();
+  let previous = (usr_db.clone(), course_db.clone());
+  let result = change(&mut usr_db, &mut course_db);
+  if let Err(e) = CasbinPolicy::update(&usr_db, &course_db) {
+    warn!("Cannot update the policies, rolling back the change.");
+    (*usr_db, *course_db) = previous;
+    if let Err(e) = CasbinPolicy::refresh(&usr_db, &course_db) {
+      debug!("{}", e);
+      error!("Cannot reload the policies after a failed update.");
+    }
+    return Err(e);
+  }
+  Ok(result)
+}
+
+/// Attributes of a request on the grades of a course. Grades without a
+/// known course, like the legacy ones, are not restricted.
+pub fn course_attributes(course_id: &str) -> RequestAttributes {
+  let now = Utc::now();
+  match COURSES_DATABASE.deref().lock().unwrap().get(course_id) {
+    Some(course) => RequestAttributes {
+      now: unix_minutes(now),
+      deadline: course.grading_deadline.map_or(i32::MAX, unix_minutes),
+      published: course.published,
+    },
+    None => RequestAttributes::unrestricted(now),
+  }
+}
+
+/// Attributes of a request on any object
+pub fn resource_attributes(resource: &str) -> RequestAttributes {
+  match course_of_resource(resource) {
+    Some(course_id) => course_attributes(course_id),
+    None => RequestAttributes::unrestricted(Utc::now()),
+  }
+}
+
 pub fn user_exits(username: &str) -> bool {
   let db = USERS_DATABASE.deref().lock().unwrap();
 
@@ -62,90 +206,351 @@ pub fn user_exits(username: &str) -> bool {
   }
 }
 
-pub fn get_student_grades(student_name: &str, requester: &User) -> Option<Vec<f32>> {
-  let db = GRADE_DATABASE.deref().lock().unwrap();
-  let resource = format!("grades/{}", student_name);
-  let is_authorized = ACCESS_CTRL.check_authorization(requester.name.as_str(), resource.as_str(), Action::Read.to_string().as_str());
+/// Return the grades of a student the requester is allowed to read.
+/// Teachers only get the grades of the courses they teach.
+pub fn get_student_grades(student_name: &str, requester: &User) -> Option<Vec<Grade>> {
+  readable_grades(student_name, requester, false)
+}
+
+/// Return the grades of a student the requester is allowed to read,
+/// deleted ones included, to reach their history.
+pub fn get_student_grade_records(student_name: &str, requester: &User) -> Option<Vec<Grade>> {
+  readable_grades(student_name, requester, true)
+}
+
+fn readable_grades(student_name: &str, requester: &User, include_deleted: bool) -> Option<Vec<Grade>> {
+  let can_read = |course_id: &str| {
+    let resource = grades_resource(student_name, course_id);
+    ACCESS_CTRL.check_authorization(requester.name.as_str(), resource.as_str(), Action::Read.to_string().as_str(), &course_attributes(course_id))
+  };
+  let is_authorized = get_courses_of_student(student_name).iter()
+    .map(|c| c.id.as_str())
+    .chain([LEGACY_COURSE_ID])
+    .any(can_read);
   if is_authorized {
-    let out = match db.get(student_name) {
-      None => None,
-      Some(val) => Some(val.clone())
-    };
-    out
+    audit::record(&requester.name, "grades.read", student_name, Outcome::Success);
+    let db = GRADE_DATABASE.deref().lock().unwrap();
+    db.get(student_name)
+      .map(|val| val.iter().filter(|g| (include_deleted || !g.deleted) && can_read(&g.course_id)).cloned().collect())
   } else {
     warn!("Unauthorized attempt to access notes of {} by {}", student_name, requester.name);
+    audit::record(&requester.name, "grades.read", student_name, Outcome::Denied);
     None
   }
 }
 
-pub fn add_grade(student_name: &str, requester: &User, grade: f32) -> Option<()>{
+pub fn add_grade(student_name: &str, requester: &User, grade: Grade) -> Option<()>{
   let mut db = GRADE_DATABASE.deref().lock().unwrap();
-  let resource= format!("grades/{}", student_name);
-  let is_authorized = ACCESS_CTRL.check_authorization(requester.name.as_str(), resource.as_str(), Action::Write.to_string().as_str());
+  let resource = grades_resource(student_name, &grade.course_id);
+  let is_authorized = ACCESS_CTRL.check_authorization(requester.name.as_str(), resource.as_str(), Action::Write.to_string().as_str(), &course_attributes(&grade.course_id));
   if is_authorized {
     let mut notes = match db.get(student_name) {
       None => vec![],
       Some(val) => val.clone(),
     };
-    notes.push(grade);
+    let course_id = grade.course_id.clone();
+    notes.push(Grade {
+      author: Some(requester.name.clone()),
+      id: next_grade_id(&notes),
+      deleted: false,
+      history: vec![],
+      ..grade
+    });
     db.insert(student_name.to_string(), notes);
-    info!("{} add a new note to {}.", requester.name, student_name);
+    info!("{} add a new note to {} in course {}.", requester.name, student_name, course_id);
+    audit::record(&requester.name, "grade.add", &grades_resource(student_name, &course_id), Outcome::Success);
     Some(())
   } else {
-    warn!("Unauthorized attempt to add note to {} by {}.", student_name, requester.name);
+    warn!("Unauthorized attempt to add note to {} in course {} by {}.", student_name, grade.course_id, requester.name);
+    audit::record(&requester.name, "grade.add", &resource, Outcome::Denied);
     None
   }
 
 }
 
-fn read_grades_db(path: &str) -> Result<HashMap<String, Vec<f32>>, Box<dyn Error>> {
-  let file = File::open(&path)?;
-  let reader = BufReader::new(file);
-  let map_res = serde_json::from_reader::<BufReader<File>,HashMap<String, Vec<f32>>>(reader);
-  let map = match map_res {
-    Ok(map) => map,
+fn grade_target(student_name: &str, grade: &Grade) -> String {
+  format!("{}#{}", grades_resource(student_name, &grade.course_id), grade.id)
+}
+
+/// Apply a change to a grade of a student if the requester may write the
+/// grades of its course.
+fn modify_grade<F>(student_name: &str, requester: &User, grade_id: u32, action: &str, op: F) -> Result<(), GradeError>
+  where F: FnOnce(&mut Grade) -> Result<(), GradeError> {
+  let mut db = GRADE_DATABASE.deref().lock().unwrap();
+  let grade = db.get_mut(student_name)
+    .and_then(|grades| grades.iter_mut().find(|g| g.id == grade_id))
+    .ok_or(GradeError::UnknownGrade)?;
+  let target = grade_target(student_name, grade);
+  let resource = grades_resource(student_name, &grade.course_id);
+  if !ACCESS_CTRL.check_authorization(requester.name.as_str(), resource.as_str(), Action::Write.to_string().as_str(), &course_attributes(&grade.course_id)) {
+    warn!("Unauthorized attempt to modify grade {} of {} by {}.", grade_id, student_name, requester.name);
+    audit::record(&requester.name, action, &target, Outcome::Denied);
+    return Err(GradeError::Unauthorized);
+  }
+  match op(grade) {
+    Ok(_) => {
+      info!("{} on grade {} of {} by {}.", action, grade_id, student_name, requester.name);
+      audit::record(&requester.name, action, &target, Outcome::Success);
+      Ok(())
+    }
+    Err(e) => {
+      audit::record(&requester.name, action, &target, Outcome::Failure);
+      Err(e)
+    }
+  }
+}
+
+pub fn edit_grade(student_name: &str, requester: &User, grade_id: u32, new_value: f32, reason: &str) -> Result<(), GradeError> {
+  modify_grade(student_name, requester, grade_id, "grade.edit", |grade| {
+    grade.edit(new_value, &requester.name, reason, Utc::now())
+  })
+}
+
+/// Mark a grade as deleted, it stays in the database with its history
+pub fn delete_grade(student_name: &str, requester: &User, grade_id: u32, reason: &str) -> Result<(), GradeError> {
+  modify_grade(student_name, requester, grade_id, "grade.delete", |grade| {
+    grade.delete(&requester.name, reason, Utc::now())
+  })
+}
+
+/// Return a grade with its revisions, deleted or not, if the requester may
+/// read the grades of its course.
+pub fn get_grade_history(student_name: &str, requester: &User, grade_id: u32) -> Result<Grade, GradeError> {
+  let db = GRADE_DATABASE.deref().lock().unwrap();
+  let grade = db.get(student_name)
+    .and_then(|grades| grades.iter().find(|g| g.id == grade_id))
+    .ok_or(GradeError::UnknownGrade)?;
+  let target = grade_target(student_name, grade);
+  let resource = grades_resource(student_name, &grade.course_id);
+  if !ACCESS_CTRL.check_authorization(requester.name.as_str(), resource.as_str(), Action::Read.to_string().as_str(), &course_attributes(&grade.course_id)) {
+    warn!("Unauthorized attempt to read the history of grade {} of {} by {}.", grade_id, student_name, requester.name);
+    audit::record(&requester.name, "grade.history", &target, Outcome::Denied);
+    return Err(GradeError::Unauthorized);
+  }
+  audit::record(&requester.name, "grade.history", &target, Outcome::Success);
+  Ok(grade.clone())
+}
+
+/// Change the grading deadline and the publication of the grades of a
+/// course. Only administrators move the deadline, the teachers of the
+/// course may publish the grades at any time. The new settings apply to the
+/// next requests, the policies do not change.
+pub fn set_course_settings(requester: &User, course_id: &str, deadline: Option<DateTime<Utc>>, published: bool) -> Result<(), CourseError> {
+  let resource = course_resource(course_id);
+  let attributes = course_attributes(course_id);
+  let mut db = COURSES_DATABASE.deref().lock().unwrap();
+  let course = db.get_mut(course_id).ok_or(CourseError::UnknownCourse)?;
+  let may_move_deadline = deadline == course.grading_deadline
+    || ACCESS_CTRL.check_authorization(requester.name.as_str(), course_resource("*").as_str(), Action::Write.to_string().as_str(), &RequestAttributes::unrestricted(Utc::now()));
+  let may_publish = published == course.published
+    || ACCESS_CTRL.check_authorization(requester.name.as_str(), resource.as_str(), Action::Publish.to_string().as_str(), &attributes);
+  if !may_move_deadline || !may_publish {
+    warn!("Unauthorized attempt to change the settings of {} by {}.", course_id, requester.name);
+    audit::record(&requester.name, "course.settings", &resource, Outcome::Denied);
+    return Err(CourseError::Unauthorized);
+  }
+  course.grading_deadline = deadline;
+  course.published = published;
+  info!("{} set the grading deadline of {} to {:?}, published: {}.", requester.name, course_id, deadline, published);
+  audit::record(&requester.name, "course.settings", &resource, Outcome::Success);
+  Ok(())
+}
+
+/// Deny a teacher any access to a course until a date, None lifts the
+/// suspension. Only administrators manage the suspensions.
+pub fn suspend_teacher(requester: &User, course_id: &str, teacher: &str, until: Option<DateTime<Utc>>) -> Result<(), CourseError> {
+  let target = format!("{}:{}", course_resource(course_id), teacher);
+  let now = Utc::now();
+  if !ACCESS_CTRL.check_authorization(requester.name.as_str(), course_resource("*").as_str(), Action::Write.to_string().as_str(), &RequestAttributes::unrestricted(now)) {
+    warn!("Unauthorized attempt to suspend {} from {} by {}.", teacher, course_id, requester.name);
+    audit::record(&requester.name, "course.suspend", &target, Outcome::Denied);
+    return Err(CourseError::Unauthorized);
+  }
+  let result = update_users_and_courses(|usr_db, course_db| {
+    let course = course_db.get_mut(course_id).ok_or(CourseError::UnknownCourse)?;
+    if !usr_db.contains_key(teacher) {
+      return Err(CourseError::UnknownUser);
+    }
+    course.suspensions.retain(|s| s.teacher != teacher);
+    if let Some(until) = until {
+      course.suspensions.push(Suspension { teacher: teacher.to_string(), until });
+    }
+    course.prune_expired(now);
+    Ok(())
+  });
+  match result {
+    Ok(Ok(())) => {
+      info!("{} suspended {} from {} until {:?}.", requester.name, teacher, course_id, until);
+      audit::record(&requester.name, "course.suspend", &target, Outcome::Success);
+      Ok(())
+    },
+    Ok(Err(e)) => Err(e),
     Err(e) => {
       debug!("{}", e);
-      info!("Cannot deserialize DB, Trying decrypt file first ...");
-      let key = read_b64_from_file("secret/key.txt")
-        .map_err(|e| {
-          debug!("{}", e);
-          error!("Cannot decrypt grades db, key not found.");
-          e
-        })?;
-      let nonce = read_b64_from_file("secret/nonce.txt")
-        .map_err(|e| {
-          debug!("{}", e);
-          error!("Cannot decrypt grades db, nonce not found.");
-          e
-        })?;
-      // TODO handle conversion error
-      let key = vec_to_key(key).unwrap();
-      let nonce = vec_to_nonce(nonce).unwrap();
-      let mut cipher = vec![];
-      let file = File::open(&path)?;
-      let mut reader = BufReader::new(file);
-      reader.read_to_end(&mut cipher)?;
-      let clear = decrypt_to_string(&cipher, &key, &nonce)?;
-      let map = serde_json::from_str(clear.as_str()).map_err(|e| {
-        error!("{} : Cannot decrypt grades db.", e);
-        e
-      })?;
-      map
+      error!("Cannot update the access control policies.");
+      audit::record(&requester.name, "course.suspend", &target, Outcome::Failure);
+      Err(CourseError::Policy)
+    },
+  }
+}
+
+/// Lend the rights of a teacher on a course to another teacher until a
+/// date, None revokes the delegation. The delegate can change the grades
+/// until the grading deadline, like the delegator.
+pub fn delegate_course(requester: &User, course_id: &str, delegate: &str, until: Option<DateTime<Utc>>) -> Result<(), CourseError> {
+  let resource = course_resource(course_id);
+  let target = format!("{}:{}", resource, delegate);
+  let now = Utc::now();
+  let attributes = course_attributes(course_id);
+  if !ACCESS_CTRL.check_authorization(requester.name.as_str(), resource.as_str(), Action::Write.to_string().as_str(), &attributes) {
+    warn!("Unauthorized attempt to delegate {} to {} by {}.", course_id, delegate, requester.name);
+    audit::record(&requester.name, "course.delegate", &target, Outcome::Denied);
+    return Err(CourseError::Unauthorized);
+  }
+  let result = update_users_and_courses(|usr_db, course_db| {
+    let course = course_db.get_mut(course_id).ok_or(CourseError::UnknownCourse)?;
+    // Administrators may write the course but have no rights to lend, and
+    // delegates cannot delegate further
+    if !course.is_taught_by(&requester.name) {
+      return Err(CourseError::Unauthorized);
     }
-  };
-  Ok(map)
+    let user = usr_db.get(delegate).ok_or(CourseError::UnknownUser)?;
+    if user.role != Role::PROF || user.name == requester.name || course.has_student(delegate) {
+      return Err(CourseError::InvalidDelegate);
+    }
+    course.delegations.retain(|d| d.delegator != requester.name || d.delegate != delegate);
+    if let Some(until) = until {
+      course.delegations.push(Delegation { delegator: requester.name.clone(), delegate: delegate.to_string(), until });
+    }
+    course.prune_expired(now);
+    Ok(())
+  });
+  match result {
+    Ok(Ok(())) => {
+      info!("{} delegated {} to {} until {:?}.", requester.name, course_id, delegate, until);
+      audit::record(&requester.name, "course.delegate", &target, Outcome::Success);
+      Ok(())
+    },
+    Ok(Err(e)) => {
+      warn!("{} cannot delegate {} to {}: {}.", requester.name, course_id, delegate, e);
+      audit::record(&requester.name, "course.delegate", &target, Outcome::Denied);
+      Err(e)
+    },
+    Err(e) => {
+      debug!("{}", e);
+      error!("Cannot update the access control policies.");
+      audit::record(&requester.name, "course.delegate", &target, Outcome::Failure);
+      Err(CourseError::Policy)
+    },
+  }
 }
 
-fn read_usr_db<P: AsRef<Path>>(
-  path: P
-) -> Result<HashMap<String, User>, Box<dyn Error>> {
-  let file = File::open(path)?;
-  let reader = BufReader::new(file);
-  let map = serde_json::from_reader(reader).map_err(|e| {
-    error!("Unable to read grades : {}",e);
-    e
-  })?;
-  Ok(map)
+/// All the courses, sorted by id
+pub fn get_courses() -> Vec<Course> {
+  let db = COURSES_DATABASE.deref().lock().unwrap();
+  let mut courses: Vec<Course> = db.values().cloned().collect();
+  courses.sort_by(|a, b| a.id.cmp(&b.id));
+  courses
 }
 
+/// Courses of a teacher, including the ones currently delegated to them
+pub fn get_courses_taught_by(teacher_name: &str) -> Vec<Course> {
+  let now = Utc::now();
+  let db = COURSES_DATABASE.deref().lock().unwrap();
+  db.values().filter(|c| c.is_taught_by(teacher_name) || c.is_delegated_to(teacher_name, now)).cloned().collect()
+}
+
+pub fn get_courses_of_student(student_name: &str) -> Vec<Course> {
+  let db = COURSES_DATABASE.deref().lock().unwrap();
+  db.values().filter(|c| c.has_student(student_name)).cloned().collect()
+}
+
+/// Map of the course ids to their title
+pub fn get_course_titles() -> HashMap<String, String> {
+  let db = COURSES_DATABASE.deref().lock().unwrap();
+  db.values().map(|c| (c.id.clone(), c.title.clone())).collect()
+}
+
+#[cfg(test)]
+mod test_db {
+  use std::fs;
+  use chrono::Duration;
+  use crate::storage::JsonStorage;
+  use crate::storage::test_storage::user;
+  use crate::user::Role;
+  use super::*;
+
+  #[test]
+  fn rekeyed_grades_must_stay_readable_from_backup() {
+    let dir = std::env::temp_dir().join(format!("king-rekey-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let mut storage = JsonStorage::new(&dir, &dir);
+    let grades = HashMap::from([("rekeyed".to_string(), vec![Grade { id: 1, ..Grade::legacy(4.5) }])]);
+    storage.save_grades(&grades).unwrap();
+    let (previous, _) = keystore::record_key("rekeyed").unwrap();
+    assert_eq!(rekey_records(&mut storage, &grades, "rekeyed").unwrap(), previous + 1);
+    assert!(keystore::record_key_by_id("rekeyed", previous).is_err());
+    assert_eq!(storage.load_grades().unwrap(), grades);
+    fs::remove_file(dir.join("grades_db.json")).unwrap();
+    assert_eq!(storage.load_grades().unwrap(), grades);
+    // Erased grades left in the backup are skipped
+    keystore::shred_record_keys("rekeyed").unwrap();
+    assert!(storage.load_grades().unwrap().is_empty());
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn only_admins_must_move_the_deadline() {
+    let admin = User { role: Role::ADMIN, ..user("csadmin") };
+    let prof = User { role: Role::PROF, ..user("csprof") };
+    let passed = Utc::now() - Duration::days(1);
+    let course = Course { grading_deadline: Some(passed), ..Course::new("CS", "", &["csprof"], &[]) };
+    update_users_and_courses(|users, courses| {
+      for u in [&admin, &prof] {
+        users.insert(u.name.clone(), u.clone());
+      }
+      courses.insert(course.id.clone(), course);
+    }).unwrap();
+    let later = Some(Utc::now() + Duration::days(1));
+    assert_eq!(set_course_settings(&prof, "CS", later, false), Err(CourseError::Unauthorized));
+    // Publication does not depend on the deadline
+    assert_eq!(set_course_settings(&prof, "CS", Some(passed), true), Ok(()));
+    assert_eq!(set_course_settings(&admin, "CS", later, true), Ok(()));
+    let course = get_courses().into_iter().find(|c| c.id == "CS").unwrap();
+    assert_eq!((course.grading_deadline, course.published), (later, true));
+  }
+
+  #[test]
+  fn only_teachers_must_modify_grades_before_the_deadline() {
+    let prof = User { role: Role::PROF, ..user("grprof") };
+    let other = User { role: Role::PROF, ..user("grother") };
+    let student = user("grstudent");
+    update_users_and_courses(|users, courses| {
+      for u in [&prof, &other, &student] {
+        users.insert(u.name.clone(), u.clone());
+      }
+      courses.insert("GR".to_string(), Course { published: true, ..Course::new("GR", "", &["grprof"], &["grstudent"]) });
+      courses.insert("GO".to_string(), Course::new("GO", "", &["grother"], &[]));
+    }).unwrap();
+    let grade = Grade { course_id: "GR".to_string(), ..Grade::legacy(4.0) };
+    assert_eq!(add_grade("grstudent", &prof, grade), Some(()));
+    let id = get_student_grades("grstudent", &prof).unwrap()[0].id;
+    assert_eq!(edit_grade("grstudent", &other, id, 6.0, "mine"), Err(GradeError::Unauthorized));
+    assert_eq!(delete_grade("grstudent", &student, id, "mine"), Err(GradeError::Unauthorized));
+    assert_eq!(edit_grade("grstudent", &prof, id, 5.0, "recount"), Ok(()));
+    let set_deadline = |deadline: Option<DateTime<Utc>>| update_users_and_courses(|_, courses| {
+      courses.get_mut("GR").unwrap().grading_deadline = deadline;
+    }).unwrap();
+    set_deadline(Some(Utc::now() - Duration::days(1)));
+    assert_eq!(edit_grade("grstudent", &prof, id, 5.5, "late"), Err(GradeError::Unauthorized));
+    assert_eq!(delete_grade("grstudent", &prof, id, "late"), Err(GradeError::Unauthorized));
+    set_deadline(None);
+    assert_eq!(delete_grade("grstudent", &prof, id, "duplicate"), Ok(()));
+    // The deleted grade is hidden but its history stays reachable
+    assert!(get_student_grades("grstudent", &prof).unwrap().is_empty());
+    let records = get_student_grade_records("grstudent", &prof).unwrap();
+    assert!(records.len() == 1 && records[0].deleted);
+    assert_eq!(get_grade_history("grstudent", &prof, id).unwrap().history.len(), 2);
+    assert_eq!(get_grade_history("grstudent", &other, id), Err(GradeError::Unauthorized));
+  }
+}
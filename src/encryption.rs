@@ -1,9 +1,9 @@
 use std::error::Error;
 use std::fs::File;
-use std::io::{Read, Write};
-use dryoc::classic::crypto_secretbox::{crypto_secretbox_easy, crypto_secretbox_keygen, crypto_secretbox_open_easy, Key, Nonce};
+use std::io::Read;
+use dryoc::classic::crypto_secretbox::{crypto_secretbox_easy, crypto_secretbox_open_easy, Key, Nonce};
 use base64::{Engine as _, engine::general_purpose};
-use dryoc::constants::{CRYPTO_SECRETBOX_MACBYTES};
+use dryoc::constants::{CRYPTO_SECRETBOX_MACBYTES, CRYPTO_SECRETBOX_NONCEBYTES};
 use std::{fmt, str};
 use dryoc::dryocsecretbox::NewByteArray;
 
@@ -16,6 +16,35 @@ impl fmt::Display for ConversionError {
   }
 }
 
+impl Error for ConversionError {}
+
+/// Magic bytes at the start of every encrypted container
+const CONTAINER_MAGIC: &[u8; 4] = b"KING";
+/// Version 1 has no key id, version 2 adds the id of the encryption key
+const CONTAINER_VERSION: u8 = 2;
+/// Key id of version 1 containers, written before the key ring existed
+const LEGACY_CONTAINER_KEY_ID: u32 = 1;
+const KEY_ID_LEN: usize = 4;
+
+#[derive(Debug, Clone)]
+pub enum ContainerError {
+  BadMagic,
+  UnsupportedVersion(u8),
+  Truncated,
+}
+
+impl fmt::Display for ContainerError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ContainerError::BadMagic => write!(f, "Not an encrypted container."),
+      ContainerError::UnsupportedVersion(v) => write!(f, "Unsupported container version {}.", v),
+      ContainerError::Truncated => write!(f, "Truncated container."),
+    }
+  }
+}
+
+impl Error for ContainerError {}
+
 
 
 pub fn encrypt_string(to_encrypt: &String, key: &Key, nonce: &Nonce) -> Result<Vec<u8>, Box<dyn Error>> {
@@ -25,27 +54,67 @@ pub fn encrypt_string(to_encrypt: &String, key: &Key, nonce: &Nonce) -> Result<V
   Ok(ciphertext)
 }
 
-pub fn decrypt_to_string(to_decrypt: &Vec<u8>, key: &Key, nonce: &Nonce) -> Result<String, Box<dyn Error>>{
+pub fn decrypt_to_string(to_decrypt: &[u8], key: &Key, nonce: &Nonce) -> Result<String, Box<dyn Error>>{
+  if to_decrypt.len() < CRYPTO_SECRETBOX_MACBYTES {
+    return Err(ConversionError.into());
+  }
   let mut decrypted = vec![0u8; to_decrypt.len() - CRYPTO_SECRETBOX_MACBYTES];
-  crypto_secretbox_open_easy(&mut decrypted, &to_decrypt, &nonce, &key)?;
+  crypto_secretbox_open_easy(&mut decrypted, to_decrypt, nonce, key)?;
   let str = str::from_utf8(&decrypted)?;
   Ok(str.to_string())
 }
 
-pub fn create_encryption_key(path: &str) -> Key {
-  let secret_key: Key = crypto_secretbox_keygen();
-  let encoded: String = general_purpose::STANDARD_NO_PAD.encode(secret_key);
-  let mut file = File::create(path).unwrap();
-  file.write(encoded.as_bytes()).unwrap();
-  secret_key
+/// Encrypt with a fresh nonce. The output is self-describing:
+/// magic bytes, format version, key id, nonce and ciphertext.
+pub fn seal_container(to_encrypt: &String, key_id: u32, key: &Key) -> Result<Vec<u8>, Box<dyn Error>> {
+  let nonce = Nonce::gen();
+  let cipher = encrypt_string(to_encrypt, key, &nonce)?;
+  let mut out = Vec::with_capacity(CONTAINER_MAGIC.len() + 1 + KEY_ID_LEN + nonce.len() + cipher.len());
+  out.extend_from_slice(CONTAINER_MAGIC);
+  out.push(CONTAINER_VERSION);
+  out.extend_from_slice(&key_id.to_be_bytes());
+  out.extend_from_slice(&nonce);
+  out.extend_from_slice(&cipher);
+  Ok(out)
 }
 
-pub fn create_nonce(path: &str) -> Nonce {
-  let nonce = Nonce::gen();
-  let encoded: String = general_purpose::STANDARD_NO_PAD.encode(nonce);
-  let mut file = File::create(path).unwrap();
-  file.write(encoded.as_bytes()).unwrap();
-  nonce
+pub fn is_container(data: &[u8]) -> bool {
+  data.starts_with(CONTAINER_MAGIC)
+}
+
+/// Parse the container header, return the key id and the offset of the nonce
+fn parse_container_header(data: &[u8]) -> Result<(u32, usize), ContainerError> {
+  if !is_container(data) {
+    return Err(ContainerError::BadMagic);
+  }
+  let version = *data.get(CONTAINER_MAGIC.len()).ok_or(ContainerError::Truncated)?;
+  let (key_id, nonce_start) = match version {
+    1 => (LEGACY_CONTAINER_KEY_ID, CONTAINER_MAGIC.len() + 1),
+    2 => {
+      let start = CONTAINER_MAGIC.len() + 1;
+      let bytes = data.get(start..start + KEY_ID_LEN).ok_or(ContainerError::Truncated)?;
+      (u32::from_be_bytes(bytes.try_into().unwrap()), start + KEY_ID_LEN)
+    }
+    v => return Err(ContainerError::UnsupportedVersion(v)),
+  };
+  if data.len() < nonce_start + CRYPTO_SECRETBOX_NONCEBYTES + CRYPTO_SECRETBOX_MACBYTES {
+    return Err(ContainerError::Truncated);
+  }
+  Ok((key_id, nonce_start))
+}
+
+/// Id of the key that encrypted the container
+pub fn container_key_id(data: &[u8]) -> Result<u32, ContainerError> {
+  parse_container_header(data).map(|(key_id, _)| key_id)
+}
+
+/// Decrypt a container produced by `seal_container`
+pub fn open_container(data: &[u8], key: &Key) -> Result<String, Box<dyn Error>> {
+  let (_, nonce_start) = parse_container_header(data)?;
+  let cipher_start = nonce_start + CRYPTO_SECRETBOX_NONCEBYTES;
+  let nonce = vec_to_nonce(data[nonce_start..cipher_start].to_vec())
+    .ok_or(ConversionError)?;
+  decrypt_to_string(&data[cipher_start..], key, &nonce)
 }
 
 pub fn read_b64_from_file(path: &str) -> Result<Vec<u8>, Box<dyn Error>>{
@@ -85,23 +154,15 @@ pub fn vec_to_nonce(nonce: Vec<u8>) -> Option<Nonce> {
 #[cfg(test)]
 mod test_encryption {
   use std::fs;
+  use dryoc::classic::crypto_secretbox::crypto_secretbox_keygen;
   use dryoc::dryocsecretbox::NewByteArray;
   use super::*;
 
-  #[test]
-  fn key_gen_must_create_key_file() {
-    let path = "db/test_key.txt";
-    create_encryption_key(path);
-    let f = File::open(path);
-    assert!(f.is_ok());
-    let res = fs::remove_file(path);
-    assert!(res.is_ok());
-  }
-
   #[test]
   fn read_key_should_return_bytes_vec() {
     let path = "db/test_key.txt";
-    create_encryption_key(path);
+    let secret_key: Key = crypto_secretbox_keygen();
+    fs::write(path, general_purpose::STANDARD_NO_PAD.encode(secret_key)).unwrap();
     let key = read_b64_from_file(path);
     assert!(key.is_ok());
     assert_eq!(key.unwrap().len(), 32);
@@ -130,4 +191,46 @@ mod test_encryption {
     assert_eq!(dec_str.unwrap().as_str(),to_encrypt);
   }
 
+  #[test]
+  fn short_cipher_must_be_rejected() {
+    let key: Key = crypto_secretbox_keygen();
+    let nonce = Nonce::gen();
+    assert!(decrypt_to_string(&[0u8; CRYPTO_SECRETBOX_MACBYTES - 1], &key, &nonce).is_err());
+    assert!(decrypt_to_string(&[], &key, &nonce).is_err());
+  }
+
+  #[test]
+  fn container_must_round_trip() {
+    let to_encrypt = "{\"alice\":[]}".to_string();
+    let key: Key = crypto_secretbox_keygen();
+    let container = seal_container(&to_encrypt, 3, &key).unwrap();
+    assert!(is_container(&container));
+    assert_eq!(container_key_id(&container).unwrap(), 3);
+    assert_eq!(open_container(&container, &key).unwrap(), to_encrypt);
+    // A fresh nonce is used for each container
+    assert_ne!(seal_container(&to_encrypt, 3, &key).unwrap(), container);
+  }
+
+  #[test]
+  fn tampered_container_must_be_rejected() {
+    let key: Key = crypto_secretbox_keygen();
+    let mut container = seal_container(&"secret".to_string(), 1, &key).unwrap();
+    let last = container.len() - 1;
+    container[last] ^= 1;
+    assert!(open_container(&container, &key).is_err());
+    assert!(open_container(b"KING", &key).is_err());
+    assert!(open_container(b"{}", &key).is_err());
+  }
+
+  #[test]
+  fn version_1_container_must_use_legacy_key_id() {
+    let key: Key = crypto_secretbox_keygen();
+    let nonce = Nonce::gen();
+    let mut container = b"KING\x01".to_vec();
+    container.extend_from_slice(&nonce);
+    container.extend_from_slice(&encrypt_string(&"old".to_string(), &key, &nonce).unwrap());
+    assert_eq!(container_key_id(&container).unwrap(), LEGACY_CONTAINER_KEY_ID);
+    assert_eq!(open_container(&container, &key).unwrap(), "old");
+  }
+
 }
\ No newline at end of file
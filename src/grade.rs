@@ -0,0 +1,219 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Serialize, Deserialize};
+
+/// Course id given to grades migrated from the former `Vec<f32>` format.
+pub const LEGACY_COURSE_ID: &str = "untagged";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Grade {
+  pub value: f32,
+  pub course_id: String,
+  pub label: String,
+  pub weight: f32,
+  pub date: Option<NaiveDate>,
+  pub author: Option<String>,
+  pub comment: Option<String>,
+  /// Identifies the grade among the grades of the student, 0 until assigned
+  #[serde(default)]
+  pub id: u32,
+  /// Deleted grades are kept for their history but no longer count
+  #[serde(default)]
+  pub deleted: bool,
+  #[serde(default)]
+  pub history: Vec<GradeRevision>,
+}
+
+/// A change made to a grade. `new_value` is None when the grade was deleted.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GradeRevision {
+  pub old_value: f32,
+  pub new_value: Option<f32>,
+  pub author: String,
+  pub timestamp: DateTime<Utc>,
+  pub reason: String,
+}
+
+impl Display for GradeRevision {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self.new_value {
+      Some(new_value) => write!(f, "{:.1} -> {:.1}", self.old_value, new_value)?,
+      None => write!(f, "{:.1} deleted", self.old_value)?,
+    }
+    write!(f, ", by {} on {}: {}", self.author, self.timestamp.format("%Y-%m-%d %H:%M"), self.reason)
+  }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum GradeError {
+  Unauthorized,
+  UnknownGrade,
+  Deleted,
+}
+
+impl Display for GradeError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      GradeError::Unauthorized => write!(f, "Unauthorized"),
+      GradeError::UnknownGrade => write!(f, "Unknown grade"),
+      GradeError::Deleted => write!(f, "The grade was deleted"),
+    }
+  }
+}
+
+impl Error for GradeError {}
+
+impl Grade {
+  /// Wrap a bare grade value from an old database into a record.
+  pub fn legacy(value: f32) -> Grade {
+    Grade {
+      value,
+      course_id: LEGACY_COURSE_ID.to_string(),
+      label: "".to_string(),
+      weight: 1.0,
+      date: None,
+      author: None,
+      comment: None,
+      id: 0,
+      deleted: false,
+      history: vec![],
+    }
+  }
+
+  pub fn is_legacy(&self) -> bool {
+    self.course_id == LEGACY_COURSE_ID
+  }
+
+  /// Change the value, keeping the previous one in the history
+  pub fn edit(&mut self, new_value: f32, author: &str, reason: &str, now: DateTime<Utc>) -> Result<(), GradeError> {
+    self.revise(Some(new_value), author, reason, now)?;
+    self.value = new_value;
+    Ok(())
+  }
+
+  pub fn delete(&mut self, author: &str, reason: &str, now: DateTime<Utc>) -> Result<(), GradeError> {
+    self.revise(None, author, reason, now)?;
+    self.deleted = true;
+    Ok(())
+  }
+
+  fn revise(&mut self, new_value: Option<f32>, author: &str, reason: &str, now: DateTime<Utc>) -> Result<(), GradeError> {
+    if self.deleted {
+      return Err(GradeError::Deleted);
+    }
+    self.history.push(GradeRevision {
+      old_value: self.value,
+      new_value,
+      author: author.to_string(),
+      timestamp: now,
+      reason: reason.to_string(),
+    });
+    Ok(())
+  }
+}
+
+/// Id for a new grade of a student
+pub fn next_grade_id(grades: &[Grade]) -> u32 {
+  grades.iter().map(|g| g.id).max().unwrap_or(0) + 1
+}
+
+/// Give an id to the grades saved before grades had one
+pub fn assign_grade_ids(grades: &mut [Grade]) {
+  let mut next = next_grade_id(grades);
+  for grade in grades.iter_mut().filter(|g| g.id == 0) {
+    grade.id = next;
+    next += 1;
+  }
+}
+
+impl Display for Grade {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{:.1} [{}] {} (weight {})", self.value, self.course_id, self.label, self.weight)?;
+    if let Some(date) = self.date {
+      write!(f, ", {}", date)?;
+    }
+    if let Some(author) = &self.author {
+      write!(f, ", by {}", author)?;
+    }
+    if let Some(comment) = &self.comment {
+      write!(f, " - {}", comment)?;
+    }
+    Ok(())
+  }
+}
+
+/// On-disk representation of a grade. Old databases store bare `f32`
+/// values, newer ones full records.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum StoredGrade {
+  Record(Grade),
+  Legacy(f32),
+}
+
+impl From<StoredGrade> for Grade {
+  fn from(stored: StoredGrade) -> Self {
+    match stored {
+      StoredGrade::Record(grade) => grade,
+      StoredGrade::Legacy(value) => Grade::legacy(value),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test_grade {
+  use std::collections::HashMap;
+  use super::*;
+
+  #[test]
+  fn legacy_values_must_be_migrated() {
+    let json = r#"{"alice":[4.5,5.0]}"#;
+    let stored: HashMap<String, Vec<StoredGrade>> = serde_json::from_str(json).unwrap();
+    let grades: Vec<Grade> = stored["alice"].iter().cloned().map(Grade::from).collect();
+    assert_eq!(grades.len(), 2);
+    assert!(grades.iter().all(|g| g.is_legacy()));
+    assert_eq!(grades[0].value, 4.5);
+    assert_eq!(grades[0].weight, 1.0);
+  }
+
+  #[test]
+  fn records_must_survive_round_trip() {
+    let grade = Grade {
+      value: 5.5,
+      course_id: "SLH".to_string(),
+      label: "Exam 1".to_string(),
+      weight: 2.0,
+      date: NaiveDate::from_ymd_opt(2023, 1, 15),
+      author: Some("prof1".to_string()),
+      comment: None,
+      id: 1,
+      deleted: false,
+      history: vec![],
+    };
+    let json = serde_json::to_string(&vec![grade.clone()]).unwrap();
+    let stored: Vec<StoredGrade> = serde_json::from_str(&json).unwrap();
+    let back: Grade = stored[0].clone().into();
+    assert_eq!(back, grade);
+    assert!(!back.is_legacy());
+  }
+
+  #[test]
+  fn edits_must_be_kept_in_history() {
+    let mut grades = vec![Grade::legacy(3.5), Grade::legacy(4.0)];
+    assign_grade_ids(&mut grades);
+    assert_eq!(grades.iter().map(|g| g.id).collect::<Vec<_>>(), vec![1, 2]);
+    assert_eq!(next_grade_id(&grades), 3);
+    let now = Utc::now();
+    let grade = &mut grades[0];
+    grade.edit(5.0, "prof1", "typo", now).unwrap();
+    grade.delete("prof1", "wrong student", now).unwrap();
+    assert_eq!(grade.value, 5.0);
+    assert!(grade.deleted);
+    assert_eq!(grade.history.len(), 2);
+    assert_eq!(grade.history[0].old_value, 3.5);
+    assert_eq!(grade.history[0].new_value, Some(5.0));
+    assert_eq!(grade.history[1].new_value, None);
+    assert_eq!(grade.edit(4.0, "prof1", "undo", now), Err(GradeError::Deleted));
+  }
+}
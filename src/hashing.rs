@@ -1,8 +1,11 @@
 use std::fmt;
-use argon2::{Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::{Algorithm, Argon2, Params, ParamsBuilder, PasswordHash, PasswordHasher, PasswordVerifier, Version};
 use argon2::password_hash::SaltString;
+use log::{debug, error};
 use once_cell::sync::Lazy;
 use rand_core::OsRng;
+use crate::config::APP_CONFIG;
+use crate::pepper::PEPPERS;
 
 #[derive(Debug)]
 pub struct PwdHasherError;
@@ -13,10 +16,73 @@ impl fmt::Display for PwdHasherError {
     }
 }
 
+impl std::error::Error for PwdHasherError {}
+
+static PWD_PARAMS: Lazy<Params> = Lazy::new(|| {
+    let config = &APP_CONFIG.password_hashing;
+    match Params::new(config.memory_kib, config.iterations, config.parallelism, None) {
+        Ok(params) => params,
+        Err(e) => {
+            debug!("{}", e);
+            error!("Invalid password hashing parameters, using defaults.");
+            Params::default()
+        }
+    }
+});
+
 static PWD_HASHER: Lazy<Argon2> = Lazy::new(|| {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, PWD_PARAMS.clone())
+});
+
+/// The key-encryption-key must always be derived with the same parameters,
+/// whatever the password hashing configuration.
+static KEK_HASHER: Lazy<Argon2> = Lazy::new(|| {
     Argon2::from(&Params::default())
 });
 
+/// Pepper id stored as the keyid of a hash, None for a hash without pepper
+fn pepper_id(params: &Params) -> Option<u32> {
+    <[u8; 4]>::try_from(params.keyid()).ok().map(u32::from_be_bytes)
+}
+
+/// Hasher using the pepper as Argon2 secret
+fn peppered_hasher(pepper: &[u8], params: Params) -> Result<Argon2<'_>, PwdHasherError> {
+    Argon2::new_with_secret(pepper, Algorithm::Argon2id, Version::V0x13, params).map_err(|e| {
+        debug!("{}", e);
+        PwdHasherError
+    })
+}
+
+/// Current parameters with the pepper id as keyid
+fn params_with_pepper_id(id: u32) -> Result<Params, PwdHasherError> {
+    let mut builder = ParamsBuilder::new();
+    builder.m_cost(PWD_PARAMS.m_cost())
+        .and_then(|b| b.t_cost(PWD_PARAMS.t_cost()))
+        .and_then(|b| b.p_cost(PWD_PARAMS.p_cost()))
+        .and_then(|b| b.keyid(&id.to_be_bytes()))
+        .map_err(|_| PwdHasherError)?;
+    builder.params().map_err(|_| PwdHasherError)
+}
+
+/// Check a password against a hash, with the pepper whose id is in the hash
+fn verify(password: &str, hash: &PasswordHash) -> bool {
+    let params = match Params::try_from(hash) {
+        Ok(val) => val,
+        Err(_) => return false,
+    };
+    match pepper_id(&params) {
+        None => PWD_HASHER.verify_password(password.as_bytes(), hash).is_ok(),
+        Some(id) => match PEPPERS.as_ref().and_then(|p| p.by_id(id)) {
+            Some(pepper) => peppered_hasher(pepper, params)
+                .map_or(false, |hasher| hasher.verify_password(password.as_bytes(), hash).is_ok()),
+            None => {
+                error!("Pepper {} of a password hash is not available.", id);
+                false
+            }
+        },
+    }
+}
+
 /// Hash the provided password and compare with pwd_and_hash.
 /// If pwd_and_hash eq. "", then a default hash is created in the aim of
 /// preserving constant-time code execution.
@@ -41,14 +107,23 @@ pub fn compare_pwd_with_hash(password: &str, pwd_and_hash: &str) -> bool {
             return false;
         },
     };
-    PWD_HASHER.verify_password(pwd_to_test.as_bytes(), &hash_to_test).is_ok()
+    verify(pwd_to_test, &hash_to_test)
 }
 
-/// Create a new hash from a new password
+/// Create a new hash from a new password, with the current pepper if any
 pub fn new_hash_from_pwd(password: &str) -> Result<String, PwdHasherError> {
     let salt = SaltString::generate(&mut OsRng);
-    match PWD_HASHER.hash_password(password.as_bytes(), &salt) {
-        Ok(val) => Ok(val.to_string()),
+    let hashed = match PEPPERS.as_ref() {
+        Some(peppers) => {
+            let (id, pepper) = peppers.current();
+            peppered_hasher(pepper, params_with_pepper_id(id)?)?
+                .hash_password(password.as_bytes(), &salt)
+                .map(|h| h.to_string())
+        }
+        None => PWD_HASHER.hash_password(password.as_bytes(), &salt).map(|h| h.to_string()),
+    };
+    match hashed {
+        Ok(val) => Ok(val),
         Err(e) => {
             println!("{}", e);
             Err(PwdHasherError)
@@ -56,3 +131,66 @@ pub fn new_hash_from_pwd(password: &str) -> Result<String, PwdHasherError> {
     }
 }
 
+
+/// Whether a stored hash is weaker than the current hashing parameters or
+/// was not made with the current pepper
+pub fn needs_rehash(pwd_and_hash: &str) -> bool {
+    let hash = match PasswordHash::new(pwd_and_hash) {
+        Ok(val) => val,
+        Err(_) => return true,
+    };
+    if hash.algorithm != Algorithm::Argon2id.ident() {
+        return true;
+    }
+    match Params::try_from(&hash) {
+        Ok(params) => weaker_than(&params, &PWD_PARAMS)
+            || pepper_id(&params) != PEPPERS.as_ref().map(|p| p.current),
+        Err(_) => true,
+    }
+}
+
+fn weaker_than(params: &Params, current: &Params) -> bool {
+    params.m_cost() < current.m_cost()
+        || params.t_cost() < current.t_cost()
+        || params.p_cost() < current.p_cost()
+}
+
+/// Derive a 32 bytes key from a passphrase, used as key-encryption-key.
+pub fn derive_key_from_pwd(passphrase: &str, salt: &[u8]) -> Result<[u8; 32], PwdHasherError> {
+    let mut key = [0u8; 32];
+    match KEK_HASHER.hash_password_into(passphrase.as_bytes(), salt, &mut key) {
+        Ok(_) => Ok(key),
+        Err(e) => {
+            debug!("{}", e);
+            Err(PwdHasherError)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_hashing {
+    use super::*;
+
+    #[test]
+    fn weaker_hashes_must_be_upgraded() {
+        let weak = Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::new(8, 1, 1, None).unwrap());
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = weak.hash_password(b"pwd", &salt).unwrap().to_string();
+        assert!(needs_rehash(&hash));
+        assert!(!needs_rehash(&new_hash_from_pwd("pwd").unwrap()));
+        let argon2i = Argon2::new(Algorithm::Argon2i, Version::V0x13, PWD_PARAMS.clone());
+        assert!(needs_rehash(&argon2i.hash_password(b"pwd", &salt).unwrap().to_string()));
+    }
+
+    #[test]
+    fn pepper_id_must_be_stored_in_hash() {
+        let salt = SaltString::generate(&mut OsRng);
+        let hasher = peppered_hasher(b"pepper", params_with_pepper_id(7).unwrap()).unwrap();
+        let hash_str = hasher.hash_password(b"pwd", &salt).unwrap().to_string();
+        let hash = PasswordHash::new(&hash_str).unwrap();
+        assert_eq!(pepper_id(&Params::try_from(&hash).unwrap()), Some(7));
+        assert!(hasher.verify_password(b"pwd", &hash).is_ok());
+        let other = peppered_hasher(b"other", PWD_PARAMS.clone()).unwrap();
+        assert!(other.verify_password(b"pwd", &hash).is_err());
+    }
+}
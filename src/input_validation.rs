@@ -1,16 +1,29 @@
 use lazy_static::lazy_static;
 use regex::Regex;
 
-static USR_NAME: &str = r"^[A-Za-z]{3,12}$";
+static USR_NAME: &str = r"^[A-Za-z][A-Za-z0-9]{2,11}$";
+static COURSE_ID: &str = r"^[A-Za-z0-9]{2,10}$";
+static LABEL: &str = r"^[\p{L}0-9 .,'()-]{1,64}$";
 
 lazy_static! {
   static ref USR_NAME_RE: Regex = Regex::new(USR_NAME).unwrap();
+  static ref COURSE_ID_RE: Regex = Regex::new(COURSE_ID).unwrap();
+  static ref LABEL_RE: Regex = Regex::new(LABEL).unwrap();
 }
 
 pub fn is_usr_n_valid(input: &String) -> bool {
   validate_input(&USR_NAME_RE, input.as_str())
 }
 
+pub fn is_course_id_valid(input: &str) -> bool {
+  validate_input(&COURSE_ID_RE, input)
+}
+
+/// Free text such as assessment labels or comments
+pub fn is_label_valid(input: &str) -> bool {
+  validate_input(&LABEL_RE, input)
+}
+
 // Input validator, uses the provided regex to check a given input validity
 fn validate_input(regex: &Regex, input: &str) -> bool {
   return regex.is_match(&input);
@@ -0,0 +1,543 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use base64::{Engine as _, engine::general_purpose};
+use dryoc::classic::crypto_secretbox::{crypto_secretbox_keygen, Key};
+use hmac::{Hmac, Mac};
+use log::{debug, error, info, warn};
+use once_cell::sync::Lazy;
+use rand_core::{OsRng, RngCore};
+use serde::de::DeserializeOwned;
+use sha2::Sha256;
+use serde::{Serialize, Deserialize};
+use crate::config::{APP_CONFIG, KeyProtection};
+use crate::encryption::{ConversionError, container_key_id, open_container, read_b64_from_file, seal_container, vec_to_key};
+use crate::hashing::derive_key_from_pwd;
+use crate::persistence::{backup_path, write_secret_atomically};
+
+/// Single data key used before the key ring
+const LEGACY_KEY_FILE: &str = "key.txt";
+const KEYRING_FILE: &str = "keyring.json";
+const WRAPPED_KEY_FILE: &str = "key.wrapped";
+const RECORD_KEYS_FILE: &str = "record_keys.json";
+const SALT_LEN: usize = 16;
+/// Key id of containers sealed with the key-encryption-key
+const KEK_KEY_ID: u32 = 0;
+
+/// Versioned data keys. The current key encrypts, all of them decrypt, so
+/// that data encrypted before a rotation stays readable.
+struct KeyRing {
+  /// Directory of the key files
+  dir: PathBuf,
+  current: u32,
+  keys: BTreeMap<u32, Key>,
+  /// Salt and key-encryption-key when the ring is wrapped with the passphrase
+  wrapping: Option<(Vec<u8>, Key)>,
+  /// No data key existed before this run, nothing was encrypted yet
+  created: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredKeyRing {
+  current: u32,
+  keys: BTreeMap<u32, String>,
+}
+
+/// Key ring encrypted with a key derived from the administrator passphrase
+#[derive(Serialize, Deserialize)]
+struct WrappedKey {
+  salt: String,
+  key: String,
+}
+
+static KEYRING: Lazy<Mutex<Option<KeyRing>>> = Lazy::new(|| Mutex::new(None));
+
+#[derive(Debug, Clone)]
+pub enum KeyRingError {
+  Locked,
+  UnknownKey(u32),
+  UnknownRecordKey(String, u32),
+}
+
+impl fmt::Display for KeyRingError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      KeyRingError::Locked => write!(f, "The data key is locked, the administrator passphrase is required."),
+      KeyRingError::UnknownKey(id) => write!(f, "Key version {} is not in the key ring.", id),
+      KeyRingError::UnknownRecordKey(owner, version) => write!(f, "Version {} of the record key of {} is missing.", version, owner),
+    }
+  }
+}
+
+impl Error for KeyRingError {}
+
+fn secret_file(dir: &Path, name: &str) -> String {
+  dir.join(name).to_string_lossy().into_owned()
+}
+
+fn read_json<T: DeserializeOwned>(path: &str) -> Result<T, Box<dyn Error>> {
+  Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+}
+
+/// Read a key file, falling back to its previous generation if it is
+/// missing or corrupted.
+fn read_json_or_backup<T: DeserializeOwned>(path: &str) -> Result<T, Box<dyn Error>> {
+  match read_json(path) {
+    Ok(val) => Ok(val),
+    Err(e) if Path::new(&backup_path(path)).exists() => {
+      debug!("{}", e);
+      error!("{} is unreadable, loading the previous generation.", path);
+      read_json(&backup_path(path))
+    }
+    Err(e) => Err(e),
+  }
+}
+
+/// Whether a key file or its previous generation exists
+fn key_file_exists(path: &str) -> bool {
+  Path::new(path).exists() || Path::new(&backup_path(path)).exists()
+}
+
+/// Return the current data key and its version.
+pub fn current_key() -> Result<(u32, Key), Box<dyn Error>> {
+  with_keyring(|ring| Ok((ring.current, ring.keys[&ring.current])))
+}
+
+/// Return the data key of the given version.
+pub fn key_by_id(key_id: u32) -> Result<Key, Box<dyn Error>> {
+  with_keyring(|ring| {
+    ring.keys.get(&key_id)
+      .copied()
+      .ok_or_else(|| KeyRingError::UnknownKey(key_id).into())
+  })
+}
+
+/// Key for another purpose derived from a master key, so that the master
+/// key is never used for two purposes.
+pub fn derived_key(key_id: u32, context: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+  let master = key_by_id(key_id)?;
+  let mut mac = Hmac::<Sha256>::new_from_slice(&master)?;
+  mac.update(context);
+  Ok(mac.finalize().into_bytes().to_vec())
+}
+
+/// Whether the data keys were created by this run, no data can then have
+/// been encrypted before
+pub fn is_first_start() -> Result<bool, Box<dyn Error>> {
+  with_keyring(|ring| Ok(ring.created))
+}
+
+/// Generate a new current data key. Previous keys are kept to read old data.
+pub fn rotate() -> Result<u32, Box<dyn Error>> {
+  with_keyring(|ring| ring.rotate())
+}
+
+/// Run f on the key ring, loading it first from the key file when the
+/// key is not protected by a passphrase.
+fn with_keyring<T>(f: impl FnOnce(&mut KeyRing) -> Result<T, Box<dyn Error>>) -> Result<T, Box<dyn Error>> {
+  let mut guard = KEYRING.lock().unwrap();
+  if guard.is_none() {
+    match APP_CONFIG.key_protection {
+      KeyProtection::Passphrase => return Err(Box::new(KeyRingError::Locked)),
+      KeyProtection::File => *guard = Some(KeyRing::load(Path::new(&APP_CONFIG.key_dir))?),
+    }
+  }
+  f(guard.as_mut().unwrap())
+}
+
+impl KeyRing {
+  /// Load the plaintext key ring of dir, or start one.
+  fn load(dir: &Path) -> Result<KeyRing, Box<dyn Error>> {
+    let path = secret_file(dir, KEYRING_FILE);
+    if key_file_exists(&path) {
+      return KeyRing::decode(dir, read_json_or_backup(&path)?, None);
+    }
+    let ring = KeyRing::legacy(dir)?;
+    ring.write()?;
+    Ok(ring)
+  }
+
+  /// Start a key ring with the legacy key file as version 1, or a new key.
+  fn legacy(dir: &Path) -> Result<KeyRing, Box<dyn Error>> {
+    let legacy_path = secret_file(dir, LEGACY_KEY_FILE);
+    let (key, created) = match read_b64_from_file(&legacy_path) {
+      Ok(val) => {
+        info!("Importing {} in the key ring.", legacy_path);
+        (vec_to_key(val).ok_or(ConversionError)?, false)
+      }
+      Err(_) => (crypto_secretbox_keygen(), true),
+    };
+    Ok(KeyRing {
+      dir: dir.to_path_buf(),
+      current: 1,
+      keys: BTreeMap::from([(1, key)]),
+      wrapping: None,
+      created,
+    })
+  }
+
+  fn decode(dir: &Path, stored: StoredKeyRing, wrapping: Option<(Vec<u8>, Key)>) -> Result<KeyRing, Box<dyn Error>> {
+    let mut keys = BTreeMap::new();
+    for (id, encoded) in stored.keys {
+      let key = general_purpose::STANDARD_NO_PAD.decode(encoded)?;
+      keys.insert(id, vec_to_key(key).ok_or(ConversionError)?);
+    }
+    if !keys.contains_key(&stored.current) {
+      return Err(Box::new(KeyRingError::UnknownKey(stored.current)));
+    }
+    Ok(KeyRing { dir: dir.to_path_buf(), current: stored.current, keys, wrapping, created: false })
+  }
+
+  /// Persist the key ring, wrapped if a passphrase protects it, keeping the
+  /// previous generation as `.bak`. The plaintext key files and their
+  /// backups are removed once their keys are in the ring.
+  fn write(&self) -> Result<(), Box<dyn Error>> {
+    let stored = StoredKeyRing {
+      current: self.current,
+      keys: self.keys.iter()
+        .map(|(id, key)| (*id, general_purpose::STANDARD_NO_PAD.encode(key)))
+        .collect(),
+    };
+    let json = serde_json::to_string(&stored)?;
+    let (path, content) = match &self.wrapping {
+      None => (secret_file(&self.dir, KEYRING_FILE), json),
+      Some((salt, kek)) => {
+        let container = seal_container(&json, KEK_KEY_ID, kek)?;
+        let wrapped = WrappedKey {
+          salt: general_purpose::STANDARD_NO_PAD.encode(salt),
+          key: general_purpose::STANDARD_NO_PAD.encode(container),
+        };
+        (secret_file(&self.dir, WRAPPED_KEY_FILE), serde_json::to_string(&wrapped)?)
+      }
+    };
+    write_secret_atomically(&path, content.as_bytes(), true)?;
+    for obsolete in [LEGACY_KEY_FILE, KEYRING_FILE] {
+      let obsolete = secret_file(&self.dir, obsolete);
+      if obsolete == path {
+        continue;
+      }
+      for file in [backup_path(&obsolete), obsolete] {
+        if fs::remove_file(&file).is_ok() {
+          info!("Removed plaintext key file {}.", file);
+        }
+      }
+    }
+    Ok(())
+  }
+
+  fn rotate(&mut self) -> Result<u32, Box<dyn Error>> {
+    let version = self.keys.keys().max().copied().unwrap_or(0) + 1;
+    self.keys.insert(version, crypto_secretbox_keygen());
+    let previous = self.current;
+    self.current = version;
+    if let Err(e) = self.write() {
+      self.current = previous;
+      self.keys.remove(&version);
+      return Err(e);
+    }
+    info!("Data key rotated from version {} to version {}.", previous, version);
+    Ok(version)
+  }
+
+  /// Unwrap the key ring of dir with the passphrase, or wrap the plaintext
+  /// key ring (or a new one) with it if none is wrapped yet.
+  fn unlock(dir: &Path, passphrase: &str) -> Result<KeyRing, Box<dyn Error>> {
+    let wrapped_path = secret_file(dir, WRAPPED_KEY_FILE);
+    if key_file_exists(&wrapped_path) {
+      let wrapped: WrappedKey = read_json_or_backup(&wrapped_path)?;
+      let salt = general_purpose::STANDARD_NO_PAD.decode(&wrapped.salt)?;
+      let container = general_purpose::STANDARD_NO_PAD.decode(&wrapped.key)?;
+      let kek = derive_key_from_pwd(passphrase, &salt)?;
+      let clear = open_container(&container, &kek)?;
+      return match serde_json::from_str::<StoredKeyRing>(&clear) {
+        Ok(stored) => KeyRing::decode(dir, stored, Some((salt, kek))),
+        Err(_) => {
+          // Single data key wrapped before the key ring existed
+          let key = general_purpose::STANDARD_NO_PAD.decode(clear)?;
+          let ring = KeyRing {
+            dir: dir.to_path_buf(),
+            current: 1,
+            keys: BTreeMap::from([(1, vec_to_key(key).ok_or(ConversionError)?)]),
+            wrapping: Some((salt, kek)),
+            created: false,
+          };
+          ring.write()?;
+          Ok(ring)
+        }
+      };
+    }
+    let mut ring = if key_file_exists(&secret_file(dir, KEYRING_FILE)) || Path::new(&secret_file(dir, LEGACY_KEY_FILE)).exists() {
+      warn!("Wrapping the existing data keys with the administrator passphrase.");
+      KeyRing::load(dir)?
+    } else {
+      KeyRing::legacy(dir)?
+    };
+    let mut salt = vec![0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let kek = derive_key_from_pwd(passphrase, &salt)?;
+    ring.wrapping = Some((salt, kek));
+    ring.write()?;
+    Ok(ring)
+  }
+}
+
+pub fn is_passphrase_set() -> bool {
+  key_file_exists(&secret_file(Path::new(&APP_CONFIG.key_dir), WRAPPED_KEY_FILE))
+}
+
+/// Unwrap the key ring with the passphrase. If no wrapped key ring exists
+/// yet, the plaintext key ring (or a new one) is wrapped with this
+/// passphrase and the plaintext key files are deleted.
+pub fn unlock_with_passphrase(passphrase: &str) -> Result<(), Box<dyn Error>> {
+  let ring = KeyRing::unlock(Path::new(&APP_CONFIG.key_dir), passphrase)?;
+  *KEYRING.lock().unwrap() = Some(ring);
+  info!("Data key unlocked.");
+  Ok(())
+}
+
+/// Data keys of the individual records (e.g. the grades of a student),
+/// versioned so that a record can be re-keyed on its own.
+struct RecordKeys {
+  current: u32,
+  keys: BTreeMap<u32, Key>,
+  /// Versions up to this one were destroyed by `shred_record_keys`, the
+  /// records they sealed were erased on purpose
+  erased: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredRecordKeys {
+  current: u32,
+  /// Record keys wrapped in containers sealed with the master key
+  keys: BTreeMap<u32, String>,
+  #[serde(default)]
+  erased: u32,
+}
+
+/// Record keys by record owner, kept in one file of the key directory
+struct RecordKeyStore {
+  dir: PathBuf,
+  records: BTreeMap<String, RecordKeys>,
+}
+
+/// Record keys, loaded on first use
+static RECORD_KEYS: Lazy<Mutex<Option<RecordKeyStore>>> = Lazy::new(|| Mutex::new(None));
+
+/// Return the current key of the owner's record and its version, a key is
+/// created on first use.
+pub fn record_key(owner: &str) -> Result<(u32, Key), Box<dyn Error>> {
+  with_record_keys(|store, ring| store.key(owner, ring))
+}
+
+/// Return a given version of the owner's record key, None if the record
+/// was erased. A key missing for any other reason is an error: the record
+/// would be lost.
+pub fn record_key_by_id(owner: &str, version: u32) -> Result<Option<Key>, Box<dyn Error>> {
+  with_record_keys(|store, _| store.key_by_id(owner, version))
+}
+
+/// Add a new current key to the owner's record. The previous versions stay
+/// until `prune_record_keys` so that the record can still be read until it
+/// has been saved with the new key.
+pub fn rotate_record_key(owner: &str) -> Result<u32, Box<dyn Error>> {
+  with_record_keys(|store, ring| store.rotate(owner, ring))
+}
+
+/// Forget all the versions of the owner's record key but the current one.
+/// Every generation of the record, backups included, must have been saved
+/// with the current key before.
+pub fn prune_record_keys(owner: &str) -> Result<(), Box<dyn Error>> {
+  with_record_keys(|store, ring| store.prune(owner, ring))
+}
+
+/// Delete every key of the owner's record, which makes the record and all
+/// its backups unreadable. The owner is remembered as erased so that these
+/// records are skipped when loading.
+pub fn shred_record_keys(owner: &str) -> Result<(), Box<dyn Error>> {
+  with_record_keys(|store, ring| store.shred(owner, ring))
+}
+
+/// Wrap the record keys again with the current master key.
+pub fn rewrap_record_keys() -> Result<(), Box<dyn Error>> {
+  with_record_keys(|store, ring| store.write(ring))
+}
+
+/// Run f on the record keys and the key ring wrapping them. The record keys
+/// are always locked before the key ring.
+fn with_record_keys<T>(f: impl FnOnce(&mut RecordKeyStore, &KeyRing) -> Result<T, Box<dyn Error>>) -> Result<T, Box<dyn Error>> {
+  let mut guard = RECORD_KEYS.lock().unwrap();
+  with_keyring(|ring| {
+    if guard.is_none() {
+      *guard = Some(RecordKeyStore::load(Path::new(&APP_CONFIG.key_dir), ring)?);
+    }
+    f(guard.as_mut().unwrap(), ring)
+  })
+}
+
+impl RecordKeyStore {
+  fn load(dir: &Path, ring: &KeyRing) -> Result<RecordKeyStore, Box<dyn Error>> {
+    let mut records = BTreeMap::new();
+    let path = secret_file(dir, RECORD_KEYS_FILE);
+    if Path::new(&path).exists() {
+      let stored: BTreeMap<String, StoredRecordKeys> = read_json(&path)?;
+      for (owner, record) in stored {
+        let mut keys = BTreeMap::new();
+        for (version, wrapped) in record.keys {
+          let container = general_purpose::STANDARD_NO_PAD.decode(wrapped)?;
+          let master_id = container_key_id(&container)?;
+          let master = ring.keys.get(&master_id).ok_or(KeyRingError::UnknownKey(master_id))?;
+          let encoded = open_container(&container, master)?;
+          let key = general_purpose::STANDARD_NO_PAD.decode(encoded)?;
+          keys.insert(version, vec_to_key(key).ok_or(ConversionError)?);
+        }
+        records.insert(owner, RecordKeys { current: record.current, keys, erased: record.erased });
+      }
+    }
+    Ok(RecordKeyStore { dir: dir.to_path_buf(), records })
+  }
+
+  /// Persist the record keys wrapped with the current master key. No backup
+  /// is kept, a destroyed key must not survive in an older generation.
+  fn write(&self, ring: &KeyRing) -> Result<(), Box<dyn Error>> {
+    let master = ring.keys[&ring.current];
+    let mut stored = BTreeMap::new();
+    for (owner, record) in self.records.iter() {
+      let mut keys = BTreeMap::new();
+      for (version, key) in record.keys.iter() {
+        let encoded = general_purpose::STANDARD_NO_PAD.encode(key);
+        let container = seal_container(&encoded, ring.current, &master)?;
+        keys.insert(*version, general_purpose::STANDARD_NO_PAD.encode(container));
+      }
+      stored.insert(owner.clone(), StoredRecordKeys { current: record.current, keys, erased: record.erased });
+    }
+    write_secret_atomically(&secret_file(&self.dir, RECORD_KEYS_FILE), serde_json::to_string(&stored)?.as_bytes(), false)
+  }
+
+  fn key(&mut self, owner: &str, ring: &KeyRing) -> Result<(u32, Key), Box<dyn Error>> {
+    if let Some(key) = self.records.get(owner).and_then(|r| r.keys.get(&r.current)) {
+      return Ok((self.records[owner].current, *key));
+    }
+    let version = self.rotate(owner, ring)?;
+    Ok((version, self.records[owner].keys[&version]))
+  }
+
+  fn key_by_id(&self, owner: &str, version: u32) -> Result<Option<Key>, Box<dyn Error>> {
+    match self.records.get(owner) {
+      Some(record) if record.keys.contains_key(&version) => Ok(Some(record.keys[&version])),
+      Some(record) if version <= record.erased => Ok(None),
+      _ => Err(Box::new(KeyRingError::UnknownRecordKey(owner.to_string(), version))),
+    }
+  }
+
+  /// Versions continue after the erased ones, so that a new owner with the
+  /// same name never reads the records of the former one.
+  fn rotate(&mut self, owner: &str, ring: &KeyRing) -> Result<u32, Box<dyn Error>> {
+    let record = self.records.entry(owner.to_string())
+      .or_insert_with(|| RecordKeys { current: 0, keys: BTreeMap::new(), erased: 0 });
+    let previous = record.current;
+    let version = previous + 1;
+    record.keys.insert(version, crypto_secretbox_keygen());
+    record.current = version;
+    if let Err(e) = self.write(ring) {
+      let record = self.records.get_mut(owner).unwrap();
+      record.keys.remove(&version);
+      record.current = previous;
+      return Err(e);
+    }
+    Ok(version)
+  }
+
+  fn prune(&mut self, owner: &str, ring: &KeyRing) -> Result<(), Box<dyn Error>> {
+    if let Some(record) = self.records.get_mut(owner) {
+      let current = record.current;
+      record.keys.retain(|version, _| *version == current);
+    }
+    self.write(ring)
+  }
+
+  fn shred(&mut self, owner: &str, ring: &KeyRing) -> Result<(), Box<dyn Error>> {
+    if let Some(record) = self.records.get_mut(owner) {
+      record.keys.clear();
+      record.erased = record.current;
+      self.write(ring)?;
+      info!("Record keys of {} destroyed.", owner);
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test_keystore {
+  use super::*;
+
+  fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("king-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  #[test]
+  fn rotated_ring_must_open_older_containers() {
+    let dir = test_dir("rotate");
+    let mut ring = KeyRing::load(&dir).unwrap();
+    let container = seal_container(&"old".to_string(), ring.current, &ring.keys[&ring.current]).unwrap();
+    assert_eq!(ring.rotate().unwrap(), 2);
+    let reloaded = KeyRing::load(&dir).unwrap();
+    assert_eq!(reloaded.current, 2);
+    let key = reloaded.keys[&container_key_id(&container).unwrap()];
+    assert_eq!(open_container(&container, &key).unwrap(), "old");
+    // The previous generation is kept and used if the key ring is corrupted
+    let path = secret_file(&dir, KEYRING_FILE);
+    fs::write(&path, "garbage").unwrap();
+    let previous = KeyRing::load(&dir).unwrap();
+    assert_eq!(previous.current, 1);
+    assert_eq!(previous.keys[&1], reloaded.keys[&1]);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn passphrase_must_wrap_and_unwrap_the_ring() {
+    let dir = test_dir("passphrase");
+    let plain = KeyRing::load(&dir).unwrap();
+    let ring = KeyRing::unlock(&dir, "correct horse").unwrap();
+    assert_eq!(ring.keys[&1], plain.keys[&1]);
+    // Only the wrapped key ring is left on disk
+    assert!(Path::new(&secret_file(&dir, WRAPPED_KEY_FILE)).exists());
+    assert!(!key_file_exists(&secret_file(&dir, KEYRING_FILE)));
+    let unwrapped = KeyRing::unlock(&dir, "correct horse").unwrap();
+    assert_eq!(unwrapped.keys[&1], plain.keys[&1]);
+    assert!(KeyRing::unlock(&dir, "wrong horse").is_err());
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn record_keys_must_remember_erased_owners() {
+    let dir = test_dir("records");
+    let ring = KeyRing::load(&dir).unwrap();
+    let mut store = RecordKeyStore::load(&dir, &ring).unwrap();
+    let (first, key) = store.key("alice", &ring).unwrap();
+    assert_eq!(store.key("alice", &ring).unwrap(), (first, key));
+    assert_eq!(store.rotate("alice", &ring).unwrap(), first + 1);
+    store.prune("alice", &ring).unwrap();
+    assert!(store.key_by_id("alice", first).is_err());
+    store.shred("alice", &ring).unwrap();
+    let mut store = RecordKeyStore::load(&dir, &ring).unwrap();
+    assert_eq!(store.key_by_id("alice", first + 1).unwrap(), None);
+    assert!(store.key_by_id("bob", 1).is_err());
+    // A new owner with the same name gets versions after the erased ones
+    let (version, new_key) = store.key("alice", &ring).unwrap();
+    assert_eq!(version, first + 2);
+    assert_ne!(new_key, key);
+    // Without the key file, the records cannot be opened anymore
+    fs::remove_file(secret_file(&dir, RECORD_KEYS_FILE)).unwrap();
+    let store = RecordKeyStore::load(&dir, &ring).unwrap();
+    assert!(store.key_by_id("alice", version).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,134 @@
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use lazy_static::__Deref;
+use log::warn;
+use serde::{Serialize, Deserialize};
+use crate::audit;
+use crate::audit::Outcome;
+use crate::config::{APP_CONFIG, LoginPolicy};
+use crate::db::{LOGIN_THROTTLE, USERS_DATABASE};
+
+/// Failed login attempts of a user, or of all the users for the global
+/// counter.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct LoginThrottle {
+  pub failures: u32,
+  pub last_failure: Option<DateTime<Utc>>,
+  pub locked_until: Option<DateTime<Utc>>,
+}
+
+impl LoginThrottle {
+  pub fn is_locked(&self, now: DateTime<Utc>) -> bool {
+    self.locked_until.map_or(false, |until| now < until)
+  }
+
+  /// Count a failed attempt of a user, return true if the account gets
+  /// locked. The counter starts again once a lockout is over.
+  pub fn record_failure(&mut self, now: DateTime<Utc>, policy: &LoginPolicy) -> bool {
+    if self.locked_until.map_or(false, |until| until <= now) {
+      *self = LoginThrottle::default();
+    }
+    self.failures += 1;
+    self.last_failure = Some(now);
+    if self.failures >= policy.max_failures && !self.is_locked(now) {
+      self.locked_until = Some(now + chrono::Duration::minutes(policy.lockout_minutes));
+      return true;
+    }
+    false
+  }
+
+  /// Count a failed attempt of any user. Failures older than the global
+  /// window are forgotten.
+  pub fn record_global_failure(&mut self, now: DateTime<Utc>, policy: &LoginPolicy) {
+    let window = chrono::Duration::minutes(policy.global_window_minutes);
+    if self.last_failure.map_or(false, |last| last + window < now) {
+      self.failures = 0;
+    }
+    self.failures += 1;
+    self.last_failure = Some(now);
+  }
+}
+
+/// Exponential back-off applied before verifying a password
+pub fn backoff_delay(failures: u32, policy: &LoginPolicy) -> Duration {
+  if failures == 0 {
+    return Duration::ZERO;
+  }
+  let factor = 2u64.saturating_pow(failures - 1);
+  Duration::from_millis(policy.backoff_base_ms.saturating_mul(factor).min(policy.backoff_max_ms))
+}
+
+/// Wait according to the failures of the user and of the whole system.
+/// Global failures only slow down the attempts, they never lock accounts
+/// so that an attacker cannot lock every user out.
+pub fn wait_before_attempt(username: &str) {
+  let policy = &APP_CONFIG.login_policy;
+  let user_failures = USERS_DATABASE.deref().lock().unwrap()
+    .get(username)
+    .map_or(0, |u| u.login_throttle.failures);
+  let global_failures = LOGIN_THROTTLE.deref().lock().unwrap().failures
+    .saturating_sub(policy.global_max_failures);
+  let delay = backoff_delay(user_failures.max(global_failures), policy);
+  if !delay.is_zero() {
+    std::thread::sleep(delay);
+  }
+}
+
+pub fn is_locked(username: &str, now: DateTime<Utc>) -> bool {
+  USERS_DATABASE.deref().lock().unwrap()
+    .get(username)
+    .map_or(false, |u| u.login_throttle.is_locked(now))
+}
+
+/// Update the counters after a login attempt
+pub fn record_attempt(username: &str, success: bool, now: DateTime<Utc>) {
+  let policy = &APP_CONFIG.login_policy;
+  let mut db = USERS_DATABASE.deref().lock().unwrap();
+  if success {
+    if let Some(user) = db.get_mut(username) {
+      user.login_throttle = LoginThrottle::default();
+    }
+    return;
+  }
+  if let Some(user) = db.get_mut(username) {
+    if user.login_throttle.record_failure(now, policy) {
+      warn!("Account {} locked after {} failed login attempts.", username, user.login_throttle.failures);
+      audit::record(username, "account.lock", username, Outcome::Success);
+    }
+  }
+  let mut global = LOGIN_THROTTLE.deref().lock().unwrap();
+  global.record_global_failure(now, policy);
+  if global.failures == policy.global_max_failures {
+    warn!("{} failed login attempts in the last {} minutes, slowing down all logins.", global.failures, policy.global_window_minutes);
+  }
+}
+
+#[cfg(test)]
+mod test_login_throttle {
+  use super::*;
+
+  #[test]
+  fn backoff_must_grow_exponentially_up_to_max() {
+    let policy = LoginPolicy::default();
+    assert_eq!(backoff_delay(0, &policy), Duration::ZERO);
+    assert_eq!(backoff_delay(1, &policy), Duration::from_millis(policy.backoff_base_ms));
+    assert_eq!(backoff_delay(3, &policy), Duration::from_millis(policy.backoff_base_ms * 4));
+    assert_eq!(backoff_delay(100, &policy), Duration::from_millis(policy.backoff_max_ms));
+  }
+
+  #[test]
+  fn account_must_be_locked_after_max_failures() {
+    let policy = LoginPolicy::default();
+    let now = Utc::now();
+    let mut throttle = LoginThrottle::default();
+    for _ in 1..policy.max_failures {
+      assert!(!throttle.record_failure(now, &policy));
+    }
+    assert!(throttle.record_failure(now, &policy));
+    assert!(throttle.is_locked(now));
+    let later = now + chrono::Duration::minutes(policy.lockout_minutes);
+    assert!(!throttle.is_locked(later));
+    assert!(!throttle.record_failure(later, &policy));
+    assert_eq!(throttle.failures, 1);
+  }
+}
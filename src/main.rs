@@ -3,80 +3,364 @@ use lazy_static::{__Deref};
 use log::{debug, error, info, warn};
 use read_input::prelude::*;
 use simplelog::{ColorChoice, Config, LevelFilter, TerminalMode, TermLogger};
-use crate::db::{USERS_DATABASE};
+use chrono::{DateTime, Duration, Local, NaiveDate, Utc};
+use crate::audit::Outcome;
+use crate::config::{APP_CONFIG, KeyProtection};
+use crate::db::{COURSES_DATABASE, USERS_DATABASE};
+use crate::grade::Grade;
 use crate::hashing::compare_pwd_with_hash;
-use crate::input_validation::is_usr_n_valid;
+use crate::password_policy::MAX_PASSWORD_LENGTH;
+use crate::input_validation::{is_course_id_valid, is_label_valid, is_usr_n_valid};
 use crate::policy_writer::CasbinPolicy;
-use crate::user::{Role, User};
+use crate::reporting::build_report_card;
+use crate::session::Session;
+use crate::user::{Action, Role, User};
+use crate::user_admin::UserAdminError;
 
 mod hashing;
 mod mocking;
 mod user;
-mod state;
 mod policy_writer;
 mod db;
 mod access_control;
 mod input_validation;
 mod encryption;
+mod grade;
+mod course;
+mod config;
+mod reporting;
+mod keystore;
+mod persistence;
+mod storage;
+mod user_admin;
+mod password_policy;
+mod login_throttle;
+mod pepper;
+mod totp;
+mod session;
+mod audit;
 
 fn usr_name_input() -> String {
-  input().add_test(|i:&String| is_usr_n_valid(i)).msg("Enter username (^(A-Za-z){3,12}$) : ").get()
+  input().add_test(|i:&String| is_usr_n_valid(i)).msg("Enter username (^[A-Za-z][A-Za-z0-9]{2,11}$) : ").get()
 }
 
 fn welcome() {
   println!("Welcome to KING: KING Is Not GAPS");
 }
 
-fn student_action(current_user: User) {
-  println!("*****\n1: See your grades\n2: About\n0: Quit");
-  let choice = input().inside(0..=1).msg("Enter Your choice: ").get();
+/// What to do after a menu action
+#[derive(PartialEq)]
+enum MenuOutcome {
+  Continue,
+  Logout,
+  Expired,
+}
+
+fn student_action(session: &mut Session) -> MenuOutcome {
+  println!("*****\n1: See your grades\n2: See the history of a grade\n3: Change password\n4: Two-factor authentication\n5: Log out / switch user\n0: Quit");
+  let choice = input().inside(0..=5).msg("Enter Your choice: ").get();
+  if !session.touch(Utc::now()) {
+    return MenuOutcome::Expired;
+  }
+  let current_user = session.user.clone();
   match choice {
     1 => show_grades(current_user.name.as_str(), &current_user),
+    2 => show_grade_history(current_user.name.as_str(), session),
+    3 => {
+      change_password(session);
+    },
+    4 => manage_two_factor(session),
+    5 => return MenuOutcome::Logout,
     0 => quit(),
     _ => panic!("impossible choice"),
   }
+  MenuOutcome::Continue
 }
 
-fn teacher_action(current_user: User) {
-  println!("*****\n1: See grades of student\n2: Enter grades\n3 About\n0: Quit");
-  let choice = input().inside(0..=2).msg("Enter Your choice : ").get();
+fn teacher_action(session: &mut Session) -> MenuOutcome {
+  println!("*****\n1: See grades of student\n2: Enter grades\n3: Edit or delete a grade\n4: See the history of a grade\n5: Course settings\n6: Delegate a course\n7: Change password\n8: Two-factor authentication\n9: Log out / switch user\n0: Quit");
+  let choice = input().inside(0..=9).msg("Enter Your choice : ").get();
+  if !session.touch(Utc::now()) {
+    return MenuOutcome::Expired;
+  }
   match choice {
     1 => {
       println!("Enter the name of the user of which you want to see the grades:");
       let name: String = input().get();
-      show_grades(name.as_str(), &current_user);
+      if still_active(session) {
+        show_grades(name.as_str(), &session.user);
+      }
+    },
+    2 => enter_grade(session),
+    3 => modify_grade(session),
+    4 => {
+      println!("Enter the name of the student:");
+      let name: String = input().get();
+      show_grade_history(name.as_str(), session);
+    },
+    5 => course_settings(session),
+    6 => delegate_course(session),
+    7 => {
+      change_password(session);
     },
-    2 => enter_grade(&current_user),
+    8 => manage_two_factor(session),
+    9 => return MenuOutcome::Logout,
     0 => quit(),
     _ => panic!("impossible choice"),
   }
+  MenuOutcome::Continue
+}
+
+fn admin_action(session: &mut Session) -> MenuOutcome {
+  println!("*****\n1: List users\n2: Create user\n3: Disable or enable user\n4: Change role\n5: Delete user\n6: Change password\n7: Two-factor authentication\n8: Explain an authorization\n9: Permissions of a user\n10: Course settings\n11: Suspend a teacher from a course\n12: Log out / switch user\n0: Quit");
+  let choice = input().inside(0..=12).msg("Enter Your choice : ").get();
+  if !session.touch(Utc::now()) {
+    return MenuOutcome::Expired;
+  }
+  let current_user = session.user.clone();
+  let current_user = &current_user;
+  let result = match choice {
+    1 => user_admin::list_users(current_user).map(|users| {
+      for u in users {
+        let state = if u.disabled { " (disabled)" } else { "" };
+        println!("  {}: {}{}", u.name, u.role, state);
+      }
+    }),
+    2 => {
+      let name = usr_name_input();
+      let password: String = input().add_test(|i: &String| i.len() <= MAX_PASSWORD_LENGTH).msg("Initial password: ").get();
+      let role = role_input();
+      if !still_active(session) {
+        return MenuOutcome::Expired;
+      }
+      user_admin::create_user(current_user, &name, &password, role)
+    },
+    3 => {
+      let name = usr_name_input();
+      let disabled = input::<String>().inside(["d".to_string(), "e".to_string()]).msg("Disable or enable (d/e): ").get() == "d";
+      if !still_active(session) {
+        return MenuOutcome::Expired;
+      }
+      user_admin::set_user_disabled(current_user, &name, disabled)
+    },
+    4 => {
+      let name = usr_name_input();
+      let role = role_input();
+      if !still_active(session) {
+        return MenuOutcome::Expired;
+      }
+      user_admin::change_role(current_user, &name, role)
+    },
+    5 => {
+      let name = usr_name_input();
+      if !still_active(session) {
+        return MenuOutcome::Expired;
+      }
+      user_admin::delete_user(current_user, &name)
+    },
+    6 => {
+      change_password(session);
+      Ok(())
+    },
+    7 => {
+      manage_two_factor(session);
+      Ok(())
+    },
+    8 => {
+      let subject: String = input().msg("Subject (user, or role:Student, role:Prof, role:Admin): ").get();
+      let resource: String = input().msg("Object (e.g. grades/alice/SLH, users): ").get();
+      let action = action_input();
+      if !still_active(session) {
+        return MenuOutcome::Expired;
+      }
+      user_admin::explain_authorization(current_user, &subject, &resource, action)
+        .map(|explanation| print!("{}", explanation))
+    },
+    9 => {
+      let name = usr_name_input();
+      if !still_active(session) {
+        return MenuOutcome::Expired;
+      }
+      user_admin::user_permissions(current_user, &name).map(|permissions| {
+        if permissions.is_empty() {
+          println!("  No permission.");
+        }
+        for p in permissions {
+          let effect = if p.allowed { "" } else { " denied" };
+          if p.policy.is_empty() {
+            println!("  {} {}{} (no policy matched)", p.action, p.resource, effect);
+          } else {
+            println!("  {} {}{} (p, {})", p.action, p.resource, effect, p.policy.join(", "));
+          }
+        }
+      })
+    },
+    10 => {
+      course_settings(session);
+      Ok(())
+    },
+    11 => {
+      suspend_teacher(session);
+      Ok(())
+    },
+    12 => return MenuOutcome::Logout,
+    0 => {
+      quit();
+      Ok(())
+    },
+    _ => panic!("impossible choice"),
+  };
+  match result {
+    Ok(_) => println!("Done."),
+    Err(e) => println!("Operation failed: {}.", e),
+  }
+  MenuOutcome::Continue
+}
+
+/// Check the idle timeout again before doing an action entered in a
+/// sub-prompt, the user may have left in the middle of it
+fn still_active(session: &mut Session) -> bool {
+  if session.touch(Utc::now()) {
+    return true;
+  }
+  println!("The action was cancelled.");
+  false
+}
+
+fn change_password(session: &mut Session) -> bool {
+  let current: String = input().add_test(|i: &String| i.len() <= MAX_PASSWORD_LENGTH).msg("Current password: ").get();
+  let new: String = input().add_test(|i: &String| i.len() <= MAX_PASSWORD_LENGTH).msg("New password: ").get();
+  let confirm: String = input().msg("Confirm the new password: ").get();
+  if new != confirm {
+    println!("The passwords do not match.");
+    return false;
+  }
+  if !still_active(session) {
+    return false;
+  }
+  match user_admin::change_password(&session.user.name, &current, &new) {
+    Ok(_) => {
+      println!("Password changed.");
+      true
+    }
+    Err(e) => {
+      println!("{}.", e);
+      false
+    }
+  }
+}
+
+/// Enroll in TOTP, or disable it when already enrolled
+fn manage_two_factor(session: &mut Session) {
+  let current_user = session.user.clone();
+  if totp::is_enrolled(&current_user.name) {
+    let code: String = input().msg("Two-factor authentication is enabled. Enter a code to disable it (empty to cancel): ").get();
+    if code.is_empty() || !still_active(session) {
+      return;
+    }
+    match totp::disable(&current_user.name, &code) {
+      Ok(_) => println!("Two-factor authentication disabled."),
+      Err(e) => println!("{}.", e),
+    }
+    return;
+  }
+  let pending = match totp::start_enrollment(&current_user.name) {
+    Ok(val) => val,
+    Err(e) => {
+      println!("{}.", e);
+      return;
+    }
+  };
+  println!("Add this account to your authenticator app:\n{}", pending.uri);
+  let code: String = input().msg("Enter the code shown by the app: ").get();
+  if !still_active(session) {
+    return;
+  }
+  match totp::confirm_enrollment(&current_user.name, &pending, &code) {
+    Ok(codes) => {
+      println!("Two-factor authentication enabled. Keep these recovery codes, each can be used once:");
+      for code in codes {
+        println!("  {}", code);
+      }
+    }
+    Err(e) => {
+      debug!("{}", e);
+      println!("Enrollment failed: {}.", e);
+    }
+  }
+}
+
+fn role_input() -> Role {
+  let choice = input().inside(1..=3).msg("Role (1: Student, 2: Prof, 3: Admin): ").get();
+  match choice {
+    1 => Role::STUDENT,
+    2 => Role::PROF,
+    3 => Role::ADMIN,
+    _ => panic!("impossible choice"),
+  }
+}
+
+fn action_input() -> Action {
+  let choice = input().inside(1..=3).msg("Action (1: Read, 2: Write, 3: Publish): ").get();
+  match choice {
+    1 => Action::Read,
+    2 => Action::Write,
+    3 => Action::Publish,
+    _ => panic!("impossible choice"),
+  }
 }
 
 fn show_grades(student_name: &str, current_user: &User) {
   if db::user_exits(student_name) {
     match db::get_student_grades(student_name, current_user) {
-      Some(grades) => {
-        println!("Here are the grades of user {}", student_name);
-        println!("{:?}", grades);
-        println!(
-          "The average is {}",
-          (grades.iter().sum::<f32>()) / ((*grades).len() as f32)
-        );
-      }
-      None => println!("No grades to show."),
+      Some(grades) if !grades.is_empty() => {
+        let titles = db::get_course_titles();
+        let card = build_report_card(student_name, &grades, &titles, APP_CONFIG.grade_rounding);
+        print!("{}", card);
+      }
+      _ => println!("No grades to show."),
     };
   } else {
     println!("User not in system");
   }
 }
 
-fn enter_grade(current_user: &User) {
+fn enter_grade(session: &mut Session) {
+  let current_user = session.user.clone();
   print!("What is the name of the student?");
   let name: String = usr_name_input();
   if db::user_exits(&name) {
     print!("What is the new grade of the student?");
-    let grade: f32 = input().add_test(|x| *x >= 0.0 && *x <= 6.0).get();
-    match db::add_grade(name.as_str(),&current_user, grade) {
+    let value: f32 = input().add_test(|x| *x >= 0.0 && *x <= 6.0).get();
+    println!("Your courses:");
+    for course in db::get_courses_taught_by(&current_user.name) {
+      let mark = if course.has_student(&name) { "" } else { " (student not enrolled)" };
+      println!("  {}: {}{}", course.id, course.title, mark);
+    }
+    let course_id: String = input().add_test(|i: &String| is_course_id_valid(i)).msg("Course id: ").get();
+    let label: String = input().add_test(|i: &String| is_label_valid(i)).msg("Assessment label: ").get();
+    let weight: f32 = input().add_test(|x| *x > 0.0 && *x <= 10.0).msg("Weight: ").get();
+    let date: String = input()
+      .add_test(|i: &String| i.is_empty() || NaiveDate::parse_from_str(i, "%Y-%m-%d").is_ok())
+      .msg("Date (YYYY-MM-DD, empty for today): ").get();
+    let date = NaiveDate::parse_from_str(&date, "%Y-%m-%d").unwrap_or(Local::now().date_naive());
+    let comment: String = input().add_test(|i: &String| i.is_empty() || is_label_valid(i)).msg("Comment (optional): ").get();
+    let grade = Grade {
+      value,
+      course_id,
+      label,
+      weight,
+      date: Some(date),
+      author: Some(current_user.name.clone()),
+      comment: if comment.is_empty() { None } else { Some(comment) },
+      id: 0,
+      deleted: false,
+      history: vec![],
+    };
+    if !still_active(session) {
+      return;
+    }
+    match db::add_grade(name.as_str(), &current_user, grade) {
       None => {
         error!("Adding note failed.");
         println!("Operation failed");
@@ -88,7 +372,163 @@ fn enter_grade(current_user: &User) {
   }
 }
 
-fn quit() {
+/// List the grades of a student the user can read and let them pick one,
+/// the deleted ones are listed too when include_deleted is set
+fn grade_id_input(student_name: &str, current_user: &User, include_deleted: bool) -> Option<u32> {
+  if !db::user_exits(student_name) {
+    println!("User not in system");
+    return None;
+  }
+  let grades = if include_deleted {
+    db::get_student_grade_records(student_name, current_user)
+  } else {
+    db::get_student_grades(student_name, current_user)
+  };
+  match grades {
+    Some(grades) if !grades.is_empty() => {
+      for grade in grades.iter() {
+        let state = if grade.deleted { " (deleted)" } else { "" };
+        println!("  #{}: {}{}", grade.id, grade, state);
+      }
+      Some(input().msg("Grade number: ").get())
+    }
+    _ => {
+      println!("No grades to show.");
+      None
+    }
+  }
+}
+
+fn modify_grade(session: &mut Session) {
+  let current_user = &session.user.clone();
+  println!("Enter the name of the student:");
+  let name: String = usr_name_input();
+  if !still_active(session) {
+    return;
+  }
+  let grade_id = match grade_id_input(&name, current_user, false) {
+    Some(id) => id,
+    None => return,
+  };
+  let edit = input::<String>().inside(["e".to_string(), "d".to_string()]).msg("Edit or delete (e/d): ").get() == "e";
+  let new_value: Option<f32> = if edit {
+    Some(input().add_test(|x| *x >= 0.0 && *x <= 6.0).msg("New grade: ").get())
+  } else {
+    None
+  };
+  let reason: String = input().add_test(|i: &String| is_label_valid(i)).msg("Reason: ").get();
+  if !still_active(session) {
+    return;
+  }
+  let result = match new_value {
+    Some(value) => db::edit_grade(&name, current_user, grade_id, value, &reason),
+    None => db::delete_grade(&name, current_user, grade_id, &reason),
+  };
+  match result {
+    Ok(_) => println!("Grade successfully modified."),
+    Err(e) => println!("Operation failed: {}.", e),
+  }
+}
+
+fn show_grade_history(student_name: &str, session: &mut Session) {
+  if !still_active(session) {
+    return;
+  }
+  let current_user = &session.user.clone();
+  let grade_id = match grade_id_input(student_name, current_user, true) {
+    Some(id) => id,
+    None => return,
+  };
+  if !still_active(session) {
+    return;
+  }
+  match db::get_grade_history(student_name, current_user, grade_id) {
+    Ok(grade) => {
+      let state = if grade.deleted { " (deleted)" } else { "" };
+      println!("{}{}", grade, state);
+      if grade.history.is_empty() {
+        println!("  Never modified.");
+      }
+      for revision in grade.history.iter() {
+        println!("  {}", revision);
+      }
+    }
+    Err(e) => println!("Operation failed: {}.", e),
+  }
+}
+
+/// Set the grading deadline of a course and publish its grades
+fn course_settings(session: &mut Session) {
+  for course in db::get_courses() {
+    let deadline = course.grading_deadline.map_or("none".to_string(), |d| d.format("%Y-%m-%d").to_string());
+    let state = if course.published { "published" } else { "not published" };
+    println!("  {}: {} (deadline {}, {})", course.id, course.title, deadline, state);
+  }
+  let course_id: String = input().add_test(|i: &String| is_course_id_valid(i)).msg("Course id: ").get();
+  let deadline = date_input("Grading deadline (YYYY-MM-DD, empty for none): ");
+  let published = input::<String>().inside(["y".to_string(), "n".to_string()]).msg("Publish the grades (y/n): ").get() == "y";
+  if !still_active(session) {
+    return;
+  }
+  match db::set_course_settings(&session.user, &course_id, deadline, published) {
+    Ok(_) => println!("Course settings saved."),
+    Err(e) => println!("Operation failed: {}.", e),
+  }
+}
+
+/// Lend the rights of the teacher on one of their courses to another
+/// teacher until a date
+fn delegate_course(session: &mut Session) {
+  let current_user = &session.user.clone();
+  for course in db::get_courses_taught_by(&current_user.name).iter().filter(|c| c.is_taught_by(&current_user.name)) {
+    println!("  {}: {}", course.id, course.title);
+    for d in course.delegations.iter().filter(|d| d.delegator == current_user.name) {
+      println!("    delegated to {} until {}", d.delegate, d.until.format("%Y-%m-%d"));
+    }
+  }
+  let course_id: String = input().add_test(|i: &String| is_course_id_valid(i)).msg("Course id: ").get();
+  let delegate = usr_name_input();
+  let until = date_input("Delegated until (YYYY-MM-DD, empty to revoke): ");
+  if !still_active(session) {
+    return;
+  }
+  match db::delegate_course(current_user, &course_id, &delegate, until) {
+    Ok(_) => println!("Delegation saved."),
+    Err(e) => println!("Operation failed: {}.", e),
+  }
+}
+
+/// Deny a teacher access to a course until a date
+fn suspend_teacher(session: &mut Session) {
+  for course in db::get_courses() {
+    println!("  {}: {} (teachers: {})", course.id, course.title, course.teachers.join(", "));
+    for s in course.suspensions.iter() {
+      println!("    {} suspended until {}", s.teacher, s.until.format("%Y-%m-%d"));
+    }
+  }
+  let course_id: String = input().add_test(|i: &String| is_course_id_valid(i)).msg("Course id: ").get();
+  let teacher = usr_name_input();
+  let until = date_input("Suspended until (YYYY-MM-DD, empty to lift the suspension): ");
+  if !still_active(session) {
+    return;
+  }
+  match db::suspend_teacher(&session.user, &course_id, &teacher, until) {
+    Ok(_) => println!("Suspension saved."),
+    Err(e) => println!("Operation failed: {}.", e),
+  }
+}
+
+/// Optional date, the returned time is the end of the day
+fn date_input(msg: &str) -> Option<DateTime<Utc>> {
+  let date: String = input()
+    .add_test(|i: &String| i.is_empty() || NaiveDate::parse_from_str(i, "%Y-%m-%d").is_ok())
+    .msg(msg).get();
+  NaiveDate::parse_from_str(&date, "%Y-%m-%d").ok()
+    .and_then(|d| d.and_hms_opt(23, 59, 59))
+    .map(|d| d.and_utc())
+}
+
+fn save() {
   match db::save_db() {
     Ok(_) => {}
     Err(e) => {
@@ -98,29 +538,233 @@ fn quit() {
       std::process::exit(1);
     }
   };
+}
+
+fn quit() {
+  save();
   std::process::exit(0);
 }
 
+/// Show the menus of the user until they log out or the session expires
+fn run_session(mut session: Session) {
+  info!("Session {} opened for {}.", session.id, session.user.name);
+  loop {
+    // An action may have found the session expired in one of its prompts
+    let outcome = if session.is_expired(Utc::now()) {
+      MenuOutcome::Expired
+    } else {
+      match session.role {
+        Role::STUDENT => student_action(&mut session),
+        Role::PROF => teacher_action(&mut session),
+        Role::ADMIN => admin_action(&mut session),
+        Role::NONE => {
+          error!("User with NONE role.");
+          MenuOutcome::Logout
+        }
+      }
+    };
+    match outcome {
+      MenuOutcome::Continue => {}
+      MenuOutcome::Logout => {
+        audit::record(&session.user.name, "logout", &session.id, Outcome::Success);
+        println!("Logged out.");
+        break;
+      }
+      MenuOutcome::Expired => {
+        warn!("Session {} of {} expired after inactivity.", session.id, session.user.name);
+        audit::record(&session.user.name, "session.expire", &session.id, Outcome::Success);
+        println!("Session expired after inactivity, please log in again.");
+        break;
+      }
+    }
+  }
+  info!("Session {} of {} closed after {} minutes.", session.id, session.user.name, (Utc::now() - session.login_time).num_minutes());
+  save();
+}
+
+/// Ask the credentials of the first administrator, no account is created
+/// with a default password
+fn create_first_admin() {
+  println!("No administrator account exists yet, create it.");
+  loop {
+    let username = usr_name_input();
+    let first: String = input().add_test(|i: &String| i.len() <= MAX_PASSWORD_LENGTH).msg("Choose the administrator password (max 31 char): ").get();
+    let second: String = input().msg("Confirm the password: ").get();
+    if first != second {
+      println!("The passwords do not match.");
+      continue;
+    }
+    match user_admin::create_first_admin(&username, &first) {
+      Ok(_) => {
+        println!("Administrator {} created.", username);
+        break;
+      }
+      Err(UserAdminError::WeakPassword(e)) => println!("Password refused, {}.", e),
+      Err(UserAdminError::ReservedName) | Err(UserAdminError::UserExists) => println!("This username cannot be used."),
+      Err(e) => {
+        error!("Cannot create the first administrator.");
+        println!("{}. Quitting...", e);
+        std::process::exit(1);
+      }
+    }
+  }
+  save();
+}
+
+/// Ask the administrator passphrase protecting the data key
+fn unlock_data_key() {
+  let passphrase: String = if keystore::is_passphrase_set() {
+    input().msg("Enter the administrator passphrase: ").get()
+  } else {
+    println!("No administrator passphrase is set yet.");
+    loop {
+      let first: String = input().add_test(|i: &String| i.len() >= 12).msg("Choose the administrator passphrase (min 12 char): ").get();
+      let second: String = input().msg("Confirm the passphrase: ").get();
+      if first == second {
+        break first;
+      }
+      println!("The passphrases do not match.");
+    }
+  };
+  if let Err(e) = keystore::unlock_with_passphrase(passphrase.as_str()) {
+    debug!("{}", e);
+    error!("Cannot unlock the data key.");
+    println!("Wrong passphrase. Quitting...");
+    std::process::exit(1);
+  }
+}
+
 fn login() -> Option<User> {
   println!("Login");
   let username: String = usr_name_input();
-  let password: String = input().add_test(|i: &String| i.len() < 32).msg("Enter your password (max 32 char): ").get();
-  let def_usr = User {
-    name: "".to_string(),
-    pwd_hash: "".to_string(),
-    role: Role::NONE,
+  let password: String = input().add_test(|i: &String| i.len() <= MAX_PASSWORD_LENGTH).msg("Enter your password (max 31 char): ").get();
+  let def_usr = User::new("", "".to_string(), Role::NONE);
+  login_throttle::wait_before_attempt(&username);
+  let now = Utc::now();
+  let locked = login_throttle::is_locked(&username, now);
+  let (success, db_rec) = {
+    let tmp = USERS_DATABASE.lock().unwrap();
+    let db_rec = tmp.get(&username).unwrap_or(&def_usr).clone();
+    // The password of a locked account is still verified to keep the same timing
+    let valid = compare_pwd_with_hash(password.as_str(), db_rec.pwd_hash.as_str());
+    (valid && !db_rec.disabled && !locked, db_rec)
   };
-  let tmp = USERS_DATABASE.lock().unwrap();
-  let db_rec = tmp.get(&username).unwrap_or(&def_usr);
-  if compare_pwd_with_hash(password.as_str(), db_rec.pwd_hash.as_str()) {
+  // The code is asked even after a wrong password so that the prompt does not
+  // tell whether the password was correct, but only checked after a correct one
+  let success = match db_rec.totp {
+    Some(_) => {
+      let code: String = input().msg("Authentication code (or recovery code): ").get();
+      success && second_factor(&username, &code)
+    }
+    None => success,
+  };
+  login_throttle::record_attempt(&username, success, now);
+  if success {
+    if let Err(e) = user_admin::upgrade_password_hash(&username, &password) {
+      debug!("{}", e);
+      error!("Cannot upgrade the password hash of {}.", username);
+    }
+  }
+  if let Err(e) = db::save_users() {
+    debug!("{}", e);
+    error!("Cannot save the login counters.");
+  }
+  if success {
     info!("Successful user authentication {}.", db_rec.name);
-    Some(db_rec.clone())
+    audit::record(&username, "login", &username, Outcome::Success);
+    Some(db_rec)
+  } else if locked {
+    warn!("Authentication attempt on locked account {}", username);
+    audit::record(&username, "login", &username, Outcome::Denied);
+    None
   } else {
     warn!("Authentication failure with username {}", username);
+    audit::record(&username, "login", &username, Outcome::Failure);
     None
   }
 }
 
+fn second_factor(username: &str, code: &str) -> bool {
+  match totp::verify_second_factor(username, code) {
+    Ok(_) => true,
+    Err(e) => {
+      debug!("{}", e);
+      warn!("Second factor failure for {}", username);
+      false
+    }
+  }
+}
+
+const USAGE: &str = "Usage: labo3 [--rotate-key | --rekey-student <name> | --erase-student <name> | --new-pepper | --verify-audit]";
+
+/// Check a user name given on the command line before anything is changed
+fn is_known_user(name: &String) -> bool {
+  is_usr_n_valid(name) && db::user_exits(name)
+}
+
+/// Administration commands run instead of the interactive menus
+fn run_command(args: &[String]) {
+  match (args[0].as_str(), args.get(1)) {
+    ("--rotate-key", None) => match db::rotate_key() {
+      Ok(version) => println!("Data key rotated, current version is {}.", version),
+      Err(e) => {
+        debug!("{}", e);
+        error!("Key rotation failed.");
+        println!("Key rotation failed, data left unchanged.");
+        std::process::exit(1);
+      }
+    },
+    ("--rekey-student", Some(name)) if !is_known_user(name) => {
+      println!("Unknown user {}.", name);
+      std::process::exit(1);
+    },
+    ("--rekey-student", Some(name)) => match db::rekey_student(name) {
+      Ok(version) => println!("Grades of {} re-encrypted, record key version is {}.", name, version),
+      Err(e) => {
+        debug!("{}", e);
+        error!("Re-keying the grades of {} failed.", name);
+        println!("Re-keying failed.");
+        std::process::exit(1);
+      }
+    },
+    ("--erase-student", Some(name)) if !is_known_user(name) => {
+      println!("Unknown user {}.", name);
+      std::process::exit(1);
+    },
+    ("--erase-student", Some(name)) => match db::erase_student_grades(db::CLI_ACTOR, name) {
+      Ok(_) => println!("Grades of {} erased.", name),
+      Err(e) => {
+        debug!("{}", e);
+        error!("Erasing the grades of {} failed.", name);
+        println!("Erasure failed.");
+        std::process::exit(1);
+      }
+    },
+    ("--verify-audit", None) => match audit::verify(&APP_CONFIG.audit_log, &audit::head_path(&APP_CONFIG.audit_log)) {
+      Ok(count) => println!("Audit log verified, {} entries.", count),
+      Err(e) => {
+        debug!("{}", e);
+        error!("Audit log verification failed.");
+        println!("Audit log verification failed: {}.", e);
+        std::process::exit(1);
+      }
+    },
+    ("--new-pepper", None) => match pepper::new_pepper() {
+      Ok(id) => println!("Pepper {} created, password hashes are upgraded on the next login.", id),
+      Err(e) => {
+        debug!("{}", e);
+        error!("Pepper creation failed.");
+        println!("Pepper creation failed.");
+        std::process::exit(1);
+      }
+    },
+    _ => {
+      println!("{}", USAGE);
+      std::process::exit(1);
+    }
+  }
+}
+
 fn main() {
   TermLogger::init(
     LevelFilter::Info,
@@ -129,33 +773,47 @@ fn main() {
     ColorChoice::Auto,
   )
     .unwrap();
+  if APP_CONFIG.key_protection == KeyProtection::Passphrase {
+    unlock_data_key();
+  }
+  let args: Vec<String> = std::env::args().skip(1).collect();
+  if !args.is_empty() {
+    run_command(&args);
+    return;
+  }
   mocking::add_users(&USERS_DATABASE);
+  mocking::add_courses(&COURSES_DATABASE);
   {
     let usr_db = USERS_DATABASE.deref().lock().unwrap();
-    match CasbinPolicy::write_to_csv(&usr_db) {
+    let course_db = COURSES_DATABASE.deref().lock().unwrap();
+    match CasbinPolicy::refresh(&usr_db, &course_db) {
       Ok(_) => {}
       Err(e) => {
         debug!("{}", e);
-        error!("Cannot write policies file.");
+        error!("Cannot load the policies.");
         println!("An error occurred. Quitting...");
         std::process::exit(1);
       }
     };
     // Unlock mutex
   }
+  if !user_admin::has_admin() {
+    create_first_admin();
+  }
 
-  welcome();
-  if let Some(user) = login() {
-    match user.role {
-      Role::STUDENT => loop {
-        student_action(user.clone())
-      },
-      Role::PROF => loop {
-        teacher_action(user.clone())
-      },
-      Role::NONE => error!("User with NONE role."),
-    }
-  } else {
-    println!("Authentication failure.");
-  };
+  loop {
+    welcome();
+    if let Some(user) = login() {
+      let idle_timeout = Duration::minutes(APP_CONFIG.session_idle_minutes);
+      let mut session = Session::new(user, idle_timeout, Utc::now());
+      if session.user.password_change_required(Utc::now()) {
+        println!("Your password was set by someone else or has expired, you must change it.");
+        while !change_password(&mut session) && !session.is_expired(Utc::now()) {}
+      }
+      run_session(session);
+    } else {
+      println!("Authentication failure.");
+      break;
+    };
+  }
 }
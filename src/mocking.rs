@@ -1,29 +1,45 @@
+use crate::course::Course;
+use crate::db::COURSES_DATABASE;
 use crate::hashing::new_hash_from_pwd;
 use crate::user::{Role, User};
 use crate::USERS_DATABASE;
 
+/// Seed the mock users on first start only, the users are then managed
+/// by the administrator. The first administrator is not a mock user, its
+/// credentials are chosen at first start.
 pub fn add_users(user_db: &USERS_DATABASE) {
   let mut map = user_db.lock().unwrap();
+  if !map.is_empty() {
+    return;
+  }
   let users = ["prof1", "prof2", "prof3"];
   for u in users {
     let pwd_hash = new_hash_from_pwd("1234")
       .expect("Unable to create mock data");
-    let usr_obj = User {
-      name: u.to_string(),
-      pwd_hash: pwd_hash.to_string(),
-      role: Role::PROF,
-    };
+    let usr_obj = User { must_change_pwd: true, ..User::new(u, pwd_hash, Role::PROF) };
     map.insert(u.to_string(), usr_obj);
   }
   let users = ["alice", "bob", "charlie", "jeff", "student1","student2"];
   for u in users {
     let pwd_hash = new_hash_from_pwd("1234")
       .expect("Unable to create mock data");
-    let usr_obj = User {
-      name: u.to_string(),
-      pwd_hash: pwd_hash.to_string(),
-      role: Role::STUDENT,
-    };
+    let usr_obj = User { must_change_pwd: true, ..User::new(u, pwd_hash, Role::STUDENT) };
     map.insert(u.to_string(), usr_obj);
   }
-}
\ No newline at end of file
+}
+
+pub fn add_courses(course_db: &COURSES_DATABASE) {
+  let mut map = course_db.lock().unwrap();
+  if !map.is_empty() {
+    return;
+  }
+  let courses = [
+    ("SLH", "Sécurité logicielle haut niveau", vec!["prof1"], vec!["alice", "bob", "charlie"]),
+    ("CRY", "Cryptographie", vec!["prof2"], vec!["alice", "jeff", "student1"]),
+    ("ALG", "Algorithmique", vec!["prof3", "prof1"], vec!["bob", "student1", "student2"]),
+  ];
+  for (id, title, teachers, students) in courses {
+    let course = Course::new(id, title, &teachers, &students);
+    map.entry(id.to_string()).or_insert(course);
+  }
+}
@@ -0,0 +1,101 @@
+use std::fmt;
+use crate::config::PasswordPolicy;
+
+/// Longest password accepted at login
+pub const MAX_PASSWORD_LENGTH: usize = 31;
+
+/// Most common passwords, compared case-insensitively
+const COMMON_PASSWORDS: &str = include_str!("common_passwords.txt");
+
+#[derive(Debug, PartialEq)]
+pub enum PasswordPolicyError {
+  TooShort(usize),
+  TooLong,
+  MissingLowercase,
+  MissingUppercase,
+  MissingDigit,
+  MissingSymbol,
+  SameAsUsername,
+  SameAsCurrent,
+  Common,
+}
+
+impl fmt::Display for PasswordPolicyError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      PasswordPolicyError::TooShort(min) => write!(f, "the password must have at least {} characters", min),
+      PasswordPolicyError::TooLong => write!(f, "the password must have at most {} characters", MAX_PASSWORD_LENGTH),
+      PasswordPolicyError::MissingLowercase => write!(f, "the password must contain a lowercase letter"),
+      PasswordPolicyError::MissingUppercase => write!(f, "the password must contain an uppercase letter"),
+      PasswordPolicyError::MissingDigit => write!(f, "the password must contain a digit"),
+      PasswordPolicyError::MissingSymbol => write!(f, "the password must contain a symbol"),
+      PasswordPolicyError::SameAsUsername => write!(f, "the password must differ from the username"),
+      PasswordPolicyError::SameAsCurrent => write!(f, "the new password must differ from the current one"),
+      PasswordPolicyError::Common => write!(f, "the password is too common"),
+    }
+  }
+}
+
+impl std::error::Error for PasswordPolicyError {}
+
+fn is_common(password: &str) -> bool {
+  COMMON_PASSWORDS.lines().any(|p| p.eq_ignore_ascii_case(password))
+}
+
+/// Check a new password of username against the policy
+pub fn check_password(username: &str, password: &str, policy: &PasswordPolicy) -> Result<(), PasswordPolicyError> {
+  let length = password.chars().count();
+  if length < policy.min_length {
+    return Err(PasswordPolicyError::TooShort(policy.min_length));
+  }
+  if password.len() > MAX_PASSWORD_LENGTH {
+    return Err(PasswordPolicyError::TooLong);
+  }
+  if policy.require_lowercase && !password.chars().any(char::is_lowercase) {
+    return Err(PasswordPolicyError::MissingLowercase);
+  }
+  if policy.require_uppercase && !password.chars().any(char::is_uppercase) {
+    return Err(PasswordPolicyError::MissingUppercase);
+  }
+  if policy.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+    return Err(PasswordPolicyError::MissingDigit);
+  }
+  if policy.require_symbol && password.chars().all(char::is_alphanumeric) {
+    return Err(PasswordPolicyError::MissingSymbol);
+  }
+  if password.eq_ignore_ascii_case(username) {
+    return Err(PasswordPolicyError::SameAsUsername);
+  }
+  if is_common(password) {
+    return Err(PasswordPolicyError::Common);
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod test_password_policy {
+  use super::*;
+
+  #[test]
+  fn strong_password_must_pass() {
+    assert_eq!(check_password("alice", "Correct7Horse", &PasswordPolicy::default()), Ok(()));
+  }
+
+  #[test]
+  fn policy_rules_must_be_enforced() {
+    let policy = PasswordPolicy::default();
+    assert_eq!(check_password("alice", "Short1", &policy), Err(PasswordPolicyError::TooShort(10)));
+    assert_eq!(check_password("alice", "lowercase123", &policy), Err(PasswordPolicyError::MissingUppercase));
+    assert_eq!(check_password("alice", "NoDigitsHere", &policy), Err(PasswordPolicyError::MissingDigit));
+    assert_eq!(check_password("alice", &"Aa1".repeat(11), &policy), Err(PasswordPolicyError::TooLong));
+    let policy = PasswordPolicy { min_length: 5, require_symbol: true, ..policy };
+    assert_eq!(check_password("alice", "Alice2024", &policy), Err(PasswordPolicyError::MissingSymbol));
+  }
+
+  #[test]
+  fn username_and_common_passwords_must_be_rejected() {
+    let policy = PasswordPolicy { min_length: 5, ..PasswordPolicy::default() };
+    assert_eq!(check_password("Prof12", "prof12", &PasswordPolicy { require_uppercase: false, ..policy.clone() }), Err(PasswordPolicyError::SameAsUsername));
+    assert_eq!(check_password("alice", "PASSWORD123", &PasswordPolicy { require_lowercase: false, ..policy }), Err(PasswordPolicyError::Common));
+  }
+}
@@ -0,0 +1,94 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use base64::{Engine as _, engine::general_purpose};
+use log::{debug, error, info};
+use once_cell::sync::Lazy;
+use rand_core::{OsRng, RngCore};
+use serde::{Serialize, Deserialize};
+use crate::audit;
+use crate::audit::Outcome;
+use crate::config::APP_CONFIG;
+use crate::db::CLI_ACTOR;
+use crate::persistence::write_secret_atomically;
+
+const PEPPER_LEN: usize = 32;
+
+/// Versioned secrets mixed in the password hashes. The current pepper is
+/// used for new hashes, the others to verify the hashes made before a
+/// rotation.
+pub struct Peppers {
+  pub current: u32,
+  pub peppers: BTreeMap<u32, Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct StoredPeppers {
+  current: u32,
+  peppers: BTreeMap<u32, String>,
+}
+
+/// Peppers of the configured pepper file, None if no pepper is configured.
+/// A configured pepper file that cannot be read is fatal: the hashes made
+/// with it could not be verified anymore.
+pub static PEPPERS: Lazy<Option<Peppers>> = Lazy::new(|| {
+  let path = APP_CONFIG.pepper_file.as_ref()?;
+  match read_peppers(path) {
+    Ok(peppers) => Some(peppers),
+    Err(e) => {
+      debug!("{}", e);
+      error!("Cannot read the pepper file {}.", path);
+      println!("Unexpected end of program.");
+      std::process::exit(1);
+    }
+  }
+});
+
+impl Peppers {
+  pub fn current(&self) -> (u32, &[u8]) {
+    (self.current, &self.peppers[&self.current])
+  }
+
+  pub fn by_id(&self, id: u32) -> Option<&[u8]> {
+    self.peppers.get(&id).map(|p| p.as_slice())
+  }
+}
+
+fn read_stored(path: &str) -> Result<StoredPeppers, Box<dyn Error>> {
+  let reader = BufReader::new(File::open(path)?);
+  Ok(serde_json::from_reader(reader)?)
+}
+
+fn read_peppers(path: &str) -> Result<Peppers, Box<dyn Error>> {
+  let stored = read_stored(path)?;
+  let mut peppers = BTreeMap::new();
+  for (id, pepper) in stored.peppers {
+    peppers.insert(id, general_purpose::STANDARD_NO_PAD.decode(pepper)?);
+  }
+  if !peppers.contains_key(&stored.current) {
+    return Err(format!("current pepper {} is missing", stored.current).into());
+  }
+  Ok(Peppers { current: stored.current, peppers })
+}
+
+/// Add a new random pepper to the pepper file and make it the current one.
+/// Hashes made with the previous peppers are upgraded on the next login.
+pub fn new_pepper() -> Result<u32, Box<dyn Error>> {
+  let path = APP_CONFIG.pepper_file.as_ref().ok_or("no pepper file is configured")?;
+  let mut stored = if Path::new(path).exists() {
+    read_stored(path)?
+  } else {
+    StoredPeppers::default()
+  };
+  let mut pepper = [0u8; PEPPER_LEN];
+  OsRng.fill_bytes(&mut pepper);
+  let id = stored.peppers.keys().max().map_or(1, |id| id + 1);
+  stored.peppers.insert(id, general_purpose::STANDARD_NO_PAD.encode(pepper));
+  stored.current = id;
+  write_secret_atomically(path, serde_json::to_string(&stored)?.as_bytes(), true)?;
+  info!("Pepper {} created in {}.", id, path);
+  audit::record(CLI_ACTOR, "pepper.create", &id.to_string(), Outcome::Success);
+  Ok(id)
+}
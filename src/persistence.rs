@@ -0,0 +1,111 @@
+use std::error::Error;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use log::trace;
+
+/// Previous generation of a file, kept by `write_atomically`
+pub fn backup_path(path: &str) -> String {
+  format!("{}.bak", path)
+}
+
+/// Replace the file at path without ever leaving it half-written:
+/// the content is written and synced to a temporary file which is then
+/// renamed over the target. With keep_backup, the previous generation is
+/// renamed to `<path>.bak` first.
+pub fn write_atomically(path: &str, content: &[u8], keep_backup: bool) -> Result<(), Box<dyn Error>> {
+  replace_file(path, content, keep_backup, false)
+}
+
+/// Same as `write_atomically` for keys and other secrets, the file and its
+/// backup are only readable by the owner.
+pub fn write_secret_atomically(path: &str, content: &[u8], keep_backup: bool) -> Result<(), Box<dyn Error>> {
+  replace_file(path, content, keep_backup, true)
+}
+
+fn replace_file(path: &str, content: &[u8], keep_backup: bool, secret: bool) -> Result<(), Box<dyn Error>> {
+  let tmp_path = format!("{}.tmp", path);
+  {
+    let mut file = if secret { create_secret(&tmp_path)? } else { File::create(&tmp_path)? };
+    file.write_all(content)?;
+    file.sync_all()?;
+  }
+  if keep_backup && Path::new(path).exists() {
+    fs::rename(path, backup_path(path))?;
+  }
+  fs::rename(&tmp_path, path)?;
+  sync_parent_dir(path)?;
+  trace!("{} written atomically.", path);
+  Ok(())
+}
+
+/// Create or truncate a file readable by the owner only. The mode is set
+/// again in case a temporary file was left with a wider one.
+#[cfg(unix)]
+fn create_secret(path: &str) -> Result<File, Box<dyn Error>> {
+  let file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+  file.set_permissions(fs::Permissions::from_mode(0o600))?;
+  Ok(file)
+}
+
+#[cfg(not(unix))]
+fn create_secret(path: &str) -> Result<File, Box<dyn Error>> {
+  Ok(File::create(path)?)
+}
+
+/// Make the renames durable
+#[cfg(unix)]
+fn sync_parent_dir(path: &str) -> Result<(), Box<dyn Error>> {
+  let parent = match Path::new(path).parent() {
+    Some(p) if !p.as_os_str().is_empty() => p,
+    _ => Path::new("."),
+  };
+  File::open(parent)?.sync_all()?;
+  Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &str) -> Result<(), Box<dyn Error>> {
+  Ok(())
+}
+
+#[cfg(test)]
+mod test_persistence {
+  use super::*;
+
+  #[test]
+  fn previous_generation_must_be_kept_as_backup() {
+    let dir = std::env::temp_dir().join(format!("king-persistence-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("db.json").to_string_lossy().into_owned();
+    write_atomically(&path, b"first", true).unwrap();
+    assert!(!Path::new(&backup_path(&path)).exists());
+    write_atomically(&path, b"second", true).unwrap();
+    write_atomically(&path, b"third", true).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), b"third");
+    assert_eq!(fs::read(backup_path(&path)).unwrap(), b"second");
+    assert!(!Path::new(&format!("{}.tmp", path)).exists());
+    write_atomically(&path, b"fourth", false).unwrap();
+    assert_eq!(fs::read(backup_path(&path)).unwrap(), b"second");
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn secrets_must_be_readable_by_the_owner_only() {
+    let dir = std::env::temp_dir().join(format!("king-persistence-secret-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("key.json").to_string_lossy().into_owned();
+    // A temporary file left by a crash keeps its wider mode otherwise
+    fs::write(format!("{}.tmp", path), b"stale").unwrap();
+    write_secret_atomically(&path, b"first", true).unwrap();
+    write_secret_atomically(&path, b"second", true).unwrap();
+    for file in [path.clone(), backup_path(&path)] {
+      assert_eq!(fs::metadata(&file).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
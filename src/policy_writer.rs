@@ -1,110 +1,207 @@
 use std::collections::HashMap;
 use std::error::Error;
-use std::{fs, io};
-use serde::Serialize;
-use crate::user::{Action, Role, User};
+use chrono::{DateTime, Utc};
+use crate::access_control::{ACCESS_CTRL, ALLOW, DENY, ENROLLMENT, POLICY, ROLE, unix_minutes};
+use crate::course::{Course, course_resource, grades_resource};
+use crate::user::{Action, Resource, Role, User};
 
-#[derive(Serialize)]
+/// Rules generated from the databases and loaded in the enforcer
+#[derive(Default, Debug, PartialEq)]
 pub struct CasbinPolicy {
-  pub p: String,
-  pub subject: String,
-  pub object: String,
-  pub actions: Action,
+  /// `p` rules: subject, object, action, condition on the request
+  /// attributes, effect
+  pub policies: Vec<Vec<String>>,
+  /// `g` rules: user, role
+  pub roles: Vec<Vec<String>>,
+  /// `g2` rules: grades of a student in a course, course
+  pub enrollments: Vec<Vec<String>>,
 }
 
-#[derive(Serialize)]
-struct CasbinGroupingPolicy {
-  pub g: String,
-  pub subject: String,
-  pub group: String,
+/// Conditions of the `p` rules, evaluated on the attributes of the request
+const ALWAYS: &str = "true";
+const BEFORE_DEADLINE: &str = "r.attrs.now <= r.attrs.deadline";
+const PUBLISHED: &str = "r.attrs.published";
+
+/// Condition of the rules lasting until a date
+fn until(time: DateTime<Utc>) -> String {
+  format!("r.attrs.now < {}", unix_minutes(time))
 }
 
-impl CasbinPolicy {
-  pub fn write_to_csv(user_db: &HashMap<String, User>) -> Result<(), Box<dyn Error>> {
-    let students: Vec<&User> = user_db.values()
-      .filter(|&u| u.role == Role::STUDENT).collect();
-    let profs: Vec<&User> = user_db.values()
-      .filter(|&u| u.role == Role::PROF).collect();
-    let mut wtr_g = csv::WriterBuilder::new()
-      .has_headers(false)
-      .from_path("accessControl/groupingPolicies.csv")?;
+/// Condition of the rules starting at a date
+fn from(time: DateTime<Utc>) -> String {
+  format!("r.attrs.now >= {}", unix_minutes(time))
+}
 
-      let mut wtr_p = csv::WriterBuilder::new()
-        .has_headers(false)
-        .from_path("accessControl/objectPolicies.csv")?;
+/// Subject of the rules of a role, kept apart from the user names which
+/// cannot contain a colon
+pub fn role_subject(role: Role) -> String {
+  format!("role:{}", role)
+}
 
-      // let mut wtr = csv::WriterBuilder::new()
-      //   .has_headers(false)
-      //   .from_path("accessControl/objectPolicies.csv")?;
-      wtr_p.serialize(
-        CasbinPolicy {
-          p: "p".to_string(),
-          subject: Role::PROF.to_string(),
-          object: "grades/all".to_string(),
-          actions: Action::Read,
-        }
-      )?;
-      wtr_p.serialize(
-        CasbinPolicy {
-          p: "p".to_string(),
-          subject: Role::PROF.to_string(),
-          object: "grades/all".to_string(),
-          actions: Action::Write,
+fn rule(fields: [&str; 5]) -> Vec<String> {
+  fields.iter().map(|f| f.to_string()).collect()
+}
+
+impl CasbinPolicy {
+  /// Teachers get access to the grades of the students enrolled in the
+  /// courses they teach, can change them until the grading deadline and
+  /// publish them. Students can read their own grades once published.
+  /// Administrators manage the users and the settings of the courses. Suspended teachers
+  /// are denied access to the course, and teachers can lend their rights
+  /// to another teacher for a while. Disabled users get nothing.
+  pub fn from_databases(user_db: &HashMap<String, User>, course_db: &HashMap<String, Course>) -> CasbinPolicy {
+    let has_role = |name: &str, role: Role| {
+      user_db.get(name).map_or(false, |u| u.role == role && !u.disabled)
+    };
+    let mut policy = CasbinPolicy::default();
+    for student in user_db.values().filter(|u| has_role(&u.name, Role::STUDENT)) {
+      policy.policies.push(rule([&student.name, &grades_resource(&student.name, "*"), &Action::Read.to_string(), PUBLISHED, ALLOW]));
+    }
+    for course in course_db.values() {
+      for teacher in course.teachers.iter().filter(|t| has_role(t, Role::PROF)) {
+        policy.policies.push(rule([teacher, &course_resource(&course.id), &Action::Read.to_string(), ALWAYS, ALLOW]));
+        policy.policies.push(rule([teacher, &course_resource(&course.id), &Action::Write.to_string(), BEFORE_DEADLINE, ALLOW]));
+        policy.policies.push(rule([teacher, &course_resource(&course.id), &Action::Publish.to_string(), ALWAYS, ALLOW]));
+      }
+      for suspension in course.suspensions.iter() {
+        for action in [Action::Read, Action::Write, Action::Publish] {
+          policy.policies.push(rule([&suspension.teacher, &course_resource(&course.id), &action.to_string(), &until(suspension.until), DENY]));
         }
-      )?;
-      for student in students {
-        wtr_p.serialize(
-          CasbinPolicy{
-            p: "p".to_string(),
-            subject: student.name.clone(),
-            object: format!("grades/{}", student.name),
-            actions: Action::Read,
-          }
-        )?;
-        wtr_g.serialize(
-          CasbinGroupingPolicy {
-            g: "g2".to_string(),
-            subject: format!("grades/{}",student.name),
-            group: "grades/all".to_string(),
-          }
-        )?;
       }
-      wtr_p.flush()?;
-
-
-      for u in profs {
-        let row = CasbinGroupingPolicy {
-          g: "g".to_string(),
-          subject: u.name.to_string(),
-          group: u.role.to_string(),
+      // A delegation ends with the rights of the delegator, and is on hold
+      // while the delegator is suspended
+      let delegations = course.delegations.iter()
+        .filter(|d| course.is_taught_by(&d.delegator) && has_role(&d.delegator, Role::PROF) && has_role(&d.delegate, Role::PROF));
+      for delegation in delegations {
+        let suspended_until = course.suspensions.iter()
+          .filter(|s| s.teacher == delegation.delegator)
+          .map(|s| s.until)
+          .max();
+        let read = match suspended_until {
+          Some(time) => format!("{} && {}", from(time), until(delegation.until)),
+          None => until(delegation.until),
         };
-        wtr_g.serialize(row)?;
+        let write = format!("{} && {}", read, BEFORE_DEADLINE);
+        policy.policies.push(rule([&delegation.delegate, &course_resource(&course.id), &Action::Read.to_string(), &read, ALLOW]));
+        policy.policies.push(rule([&delegation.delegate, &course_resource(&course.id), &Action::Write.to_string(), &write, ALLOW]));
+      }
+      for student in course.students.iter() {
+        policy.enrollments.push(vec![grades_resource(student, &course.id), course_resource(&course.id)]);
       }
-      wtr_g.flush()?;
+    }
+    for action in [Action::Read, Action::Write] {
+      policy.policies.push(rule([&role_subject(Role::ADMIN), &Resource::USERS.to_string(), &action.to_string(), ALWAYS, ALLOW]));
+    }
+    // Only the course objects themselves match, grades are grouped by
+    // course with g2 which does not expand patterns
+    for action in [Action::Write, Action::Publish] {
+      policy.policies.push(rule([&role_subject(Role::ADMIN), &course_resource("*"), &action.to_string(), ALWAYS, ALLOW]));
+    }
+    for user in user_db.values().filter(|u| has_role(&u.name, Role::PROF) || has_role(&u.name, Role::ADMIN)) {
+      policy.roles.push(vec![user.name.clone(), role_subject(user.role)]);
+    }
+    // Casbin refuses a batch containing a rule twice, e.g. a teacher listed
+    // twice in a course
+    for rules in [&mut policy.policies, &mut policy.roles, &mut policy.enrollments] {
+      rules.sort();
+      rules.dedup();
+    }
+    policy
+  }
+
+  /// Rules of each policy type
+  pub fn into_rules(self) -> [(&'static str, Vec<Vec<String>>); 3] {
+    [(POLICY, self.policies), (ROLE, self.roles), (ENROLLMENT, self.enrollments)]
+  }
+
+  /// Generate the policies from the databases and load them
+  pub fn refresh(user_db: &HashMap<String, User>, course_db: &HashMap<String, Course>) -> Result<(), Box<dyn Error>> {
+    ACCESS_CTRL.load(CasbinPolicy::from_databases(user_db, course_db))?;
+    Ok(())
+  }
 
-    CasbinPolicy::merge_policy_files()?;
+  /// Push the rules changed since the last update to the enforcer
+  pub fn update(user_db: &HashMap<String, User>, course_db: &HashMap<String, Course>) -> Result<(), Box<dyn Error>> {
+    ACCESS_CTRL.update(CasbinPolicy::from_databases(user_db, course_db))?;
     Ok(())
   }
+}
 
-  /// Juste a trick because csv writer cannot write lines with
-  /// different line numbers
-  fn merge_policy_files() -> Result<(), Box<dyn Error>> {
-    let mut out = fs::OpenOptions::new()
-      .append(true)
-      .create(true)
-      .open("accessControl/policies.csv")?;
+#[cfg(test)]
+mod test_policy_writer {
+  use chrono::Duration;
+  use futures::executor::block_on;
+  use super::*;
+  use crate::access_control::{AccessControl, RequestAttributes};
+  use crate::course::{Delegation, Suspension};
+  use crate::storage::test_storage;
 
-    let mut obj_pol = fs::OpenOptions::new()
-      .read(true)
-      .open("accessControl/objectPolicies.csv")?;
+  fn user(name: &str, role: Role) -> User {
+    User { role, ..test_storage::user(name) }
+  }
 
-    let mut gr_pol = fs::OpenOptions::new()
-      .read(true)
-      .open("accessControl/groupingPolicies.csv")?;
+  #[test]
+  fn policies_must_not_contain_duplicates() {
+    let mut users = HashMap::new();
+    for u in [user("prof1", Role::PROF), user("alice", Role::STUDENT)] {
+      users.insert(u.name.clone(), u);
+    }
+    let course = Course::new("SLH", "", &["prof1", "prof1"], &["alice"]);
+    let courses = HashMap::from([(course.id.clone(), course)]);
+    let policy = CasbinPolicy::from_databases(&users, &courses);
+    assert_eq!(policy, CasbinPolicy::from_databases(&users, &courses));
+    assert_eq!(policy.policies.len(), 8);
+    assert_eq!(policy.roles, vec![vec!["prof1".to_string(), "role:Prof".to_string()]]);
+    assert_eq!(policy.enrollments, vec![vec!["grades/alice/SLH".to_string(), "courses/SLH".to_string()]]);
+  }
 
-    io::copy(&mut obj_pol, &mut out)?;
-    io::copy(&mut gr_pol, &mut out)?;
+  #[test]
+  fn delegation_must_be_on_hold_while_the_delegator_is_suspended() {
+    let mut users = HashMap::new();
+    for u in [user("prof1", Role::PROF), user("prof2", Role::PROF), user("alice", Role::STUDENT)] {
+      users.insert(u.name.clone(), u);
+    }
+    let now = Utc::now();
+    let course = Course {
+      suspensions: vec![Suspension { teacher: "prof1".to_string(), until: now + Duration::days(1) }],
+      delegations: vec![Delegation { delegator: "prof1".to_string(), delegate: "prof2".to_string(), until: now + Duration::days(2) }],
+      ..Course::new("SLH", "", &["prof1"], &["alice"])
+    };
+    let courses = HashMap::from([(course.id.clone(), course)]);
+    let access_ctrl = block_on(AccessControl::new()).unwrap();
+    access_ctrl.load(CasbinPolicy::from_databases(&users, &courses)).unwrap();
+    let at = |days: i64| RequestAttributes::unrestricted(now + Duration::days(days) + Duration::hours(1));
+    for action in ["Read", "Write"] {
+      assert!(!access_ctrl.check_authorization("prof1", "grades/alice/SLH", action, &at(0)));
+      assert!(!access_ctrl.check_authorization("prof2", "grades/alice/SLH", action, &at(0)));
+      assert!(access_ctrl.check_authorization("prof1", "grades/alice/SLH", action, &at(1)));
+      assert!(access_ctrl.check_authorization("prof2", "grades/alice/SLH", action, &at(1)));
+      assert!(!access_ctrl.check_authorization("prof2", "grades/alice/SLH", action, &at(2)));
+    }
+  }
 
-    Ok(())
+  #[test]
+  fn teachers_must_only_reach_their_courses() {
+    let mut users = HashMap::new();
+    for u in [user("prof1", Role::PROF), user("prof2", Role::PROF), user("alice", Role::STUDENT), user("bob", Role::STUDENT)] {
+      users.insert(u.name.clone(), u);
+    }
+    let courses = HashMap::from([
+      ("SLH".to_string(), Course::new("SLH", "", &["prof1"], &["alice"])),
+      ("CRY".to_string(), Course::new("CRY", "", &["prof2"], &["bob"])),
+    ]);
+    let access_ctrl = block_on(AccessControl::new()).unwrap();
+    access_ctrl.load(CasbinPolicy::from_databases(&users, &courses)).unwrap();
+    let attrs = RequestAttributes::unrestricted(Utc::now());
+    for action in ["Read", "Write"] {
+      assert!(access_ctrl.check_authorization("prof1", "grades/alice/SLH", action, &attrs));
+      // Student of another teacher, in the other course or in none
+      assert!(!access_ctrl.check_authorization("prof1", "grades/bob/CRY", action, &attrs));
+      assert!(!access_ctrl.check_authorization("prof1", "grades/bob/SLH", action, &attrs));
+      assert!(!access_ctrl.check_authorization("prof1", "grades/alice/CRY", action, &attrs));
+    }
+    assert!(!access_ctrl.check_authorization("prof1", "courses/CRY", "Write", &attrs));
+    assert!(!access_ctrl.check_authorization("prof1", "courses/*", "Write", &attrs));
+    assert!(!access_ctrl.check_authorization("prof1", "users", "Read", &attrs));
   }
-}
\ No newline at end of file
+}
@@ -0,0 +1,170 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use crate::config::Rounding;
+use crate::grade::Grade;
+
+pub const PASSING_GRADE: f32 = 4.0;
+const MIN_GRADE: f32 = 1.0;
+const MAX_GRADE: f32 = 6.0;
+
+pub struct CourseReport {
+  pub course_id: String,
+  pub title: Option<String>,
+  pub grades: Vec<Grade>,
+  /// Rounded weighted average, None when the course has no weighted grade
+  pub average: Option<f32>,
+}
+
+impl CourseReport {
+  pub fn is_failing(&self) -> bool {
+    matches!(self.average, Some(avg) if avg < PASSING_GRADE)
+  }
+}
+
+pub struct ReportCard {
+  pub student: String,
+  pub courses: Vec<CourseReport>,
+  /// Average of the course averages, rounded
+  pub overall: Option<f32>,
+}
+
+impl ReportCard {
+  pub fn failing_courses(&self) -> Vec<&CourseReport> {
+    self.courses.iter().filter(|c| c.is_failing()).collect()
+  }
+}
+
+/// Weighted mean of the grades, None if the total weight is null.
+pub fn weighted_average(grades: &[Grade]) -> Option<f32> {
+  let total_weight: f32 = grades.iter().map(|g| g.weight).sum();
+  if total_weight <= 0.0 {
+    return None;
+  }
+  let sum: f32 = grades.iter().map(|g| g.value * g.weight).sum();
+  Some(sum / total_weight)
+}
+
+/// Round a grade to the configured precision and keep it in the 1-6 range.
+pub fn round_grade(value: f32, rounding: Rounding) -> f32 {
+  let steps = match rounding {
+    Rounding::Half => 2.0,
+    Rounding::Tenth => 10.0,
+  };
+  ((value * steps).round() / steps).clamp(MIN_GRADE, MAX_GRADE)
+}
+
+/// Group the grades by course and compute the averages.
+/// `titles` maps course ids to their title.
+pub fn build_report_card(
+  student: &str,
+  grades: &[Grade],
+  titles: &HashMap<String, String>,
+  rounding: Rounding,
+) -> ReportCard {
+  let mut by_course: BTreeMap<&str, Vec<Grade>> = BTreeMap::new();
+  for grade in grades {
+    by_course.entry(grade.course_id.as_str()).or_default().push(grade.clone());
+  }
+  let courses: Vec<CourseReport> = by_course.into_iter()
+    .map(|(course_id, grades)| CourseReport {
+      course_id: course_id.to_string(),
+      title: titles.get(course_id).cloned(),
+      average: weighted_average(&grades).map(|avg| round_grade(avg, rounding)),
+      grades,
+    })
+    .collect();
+  let averages: Vec<f32> = courses.iter().filter_map(|c| c.average).collect();
+  let overall = if averages.is_empty() {
+    None
+  } else {
+    let mean = averages.iter().sum::<f32>() / averages.len() as f32;
+    Some(round_grade(mean, rounding))
+  };
+  ReportCard {
+    student: student.to_string(),
+    courses,
+    overall,
+  }
+}
+
+fn format_average(average: Option<f32>) -> String {
+  match average {
+    Some(avg) => format!("{:.1}", avg),
+    None => "-".to_string(),
+  }
+}
+
+impl Display for ReportCard {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    writeln!(f, "Report card of {}", self.student)?;
+    writeln!(f, "{}", "=".repeat(60))?;
+    for course in self.courses.iter() {
+      let title = course.title.as_deref().unwrap_or("");
+      let flag = if course.is_failing() { "  FAILED" } else { "" };
+      writeln!(f, "{:<6} {:<40} {:>5}{}", course.course_id, title, format_average(course.average), flag)?;
+      for grade in course.grades.iter() {
+        let date = grade.date.map(|d| d.to_string()).unwrap_or_default();
+        writeln!(f, "    {:<30} {:>10} x{:<4} {:>5.1}", grade.label, date, grade.weight, grade.value)?;
+      }
+    }
+    writeln!(f, "{}", "-".repeat(60))?;
+    writeln!(f, "{:<47} {:>5}", "Overall average", format_average(self.overall))?;
+    let failing = self.failing_courses();
+    if !failing.is_empty() {
+      let ids: Vec<&str> = failing.iter().map(|c| c.course_id.as_str()).collect();
+      writeln!(f, "Failing courses: {}", ids.join(", "))?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test_reporting {
+  use super::*;
+
+  fn grade(course_id: &str, value: f32, weight: f32) -> Grade {
+    Grade {
+      value,
+      course_id: course_id.to_string(),
+      label: "Test".to_string(),
+      weight,
+      date: None,
+      author: None,
+      comment: None,
+      id: 0,
+      deleted: false,
+      history: vec![],
+    }
+  }
+
+  #[test]
+  fn weighted_average_must_use_weights() {
+    let grades = vec![grade("SLH", 6.0, 3.0), grade("SLH", 2.0, 1.0)];
+    assert_eq!(weighted_average(&grades), Some(5.0));
+  }
+
+  #[test]
+  fn weighted_average_of_nothing_is_none() {
+    assert_eq!(weighted_average(&[]), None);
+    assert_eq!(weighted_average(&[grade("SLH", 5.0, 0.0)]), None);
+  }
+
+  #[test]
+  fn rounding_must_follow_swiss_rules() {
+    assert_eq!(round_grade(4.74, Rounding::Half), 4.5);
+    assert_eq!(round_grade(4.75, Rounding::Half), 5.0);
+    assert_eq!(round_grade(4.74, Rounding::Tenth), 4.7);
+    assert_eq!(round_grade(0.2, Rounding::Tenth), 1.0);
+  }
+
+  #[test]
+  fn report_card_must_flag_failing_courses() {
+    let grades = vec![grade("SLH", 3.5, 1.0), grade("CRY", 5.0, 1.0), grade("CRY", 5.5, 1.0)];
+    let card = build_report_card("alice", &grades, &HashMap::new(), Rounding::Half);
+    assert_eq!(card.courses.len(), 2);
+    let failing = card.failing_courses();
+    assert_eq!(failing.len(), 1);
+    assert_eq!(failing[0].course_id, "SLH");
+    assert_eq!(card.overall, Some(4.5));
+  }
+}
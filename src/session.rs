@@ -0,0 +1,63 @@
+use base64::{Engine as _, engine::general_purpose};
+use chrono::{DateTime, Duration, Utc};
+use rand_core::{OsRng, RngCore};
+use crate::user::{Role, User};
+
+const SESSION_ID_LEN: usize = 16;
+
+/// A logged-in user. The session ends when the user logs out or stays
+/// idle longer than the timeout.
+pub struct Session {
+  pub id: String,
+  pub user: User,
+  pub role: Role,
+  pub login_time: DateTime<Utc>,
+  last_activity: DateTime<Utc>,
+  idle_timeout: Duration,
+}
+
+impl Session {
+  pub fn new(user: User, idle_timeout: Duration, now: DateTime<Utc>) -> Session {
+    let mut bytes = [0u8; SESSION_ID_LEN];
+    OsRng.fill_bytes(&mut bytes);
+    Session {
+      id: general_purpose::URL_SAFE_NO_PAD.encode(bytes),
+      role: user.role,
+      user,
+      login_time: now,
+      last_activity: now,
+      idle_timeout,
+    }
+  }
+
+  pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+    now - self.last_activity > self.idle_timeout
+  }
+
+  /// Record an action of the user, return false if the session expired
+  /// in the meantime.
+  pub fn touch(&mut self, now: DateTime<Utc>) -> bool {
+    if self.is_expired(now) {
+      return false;
+    }
+    self.last_activity = now;
+    true
+  }
+}
+
+#[cfg(test)]
+mod test_session {
+  use super::*;
+  use crate::storage::test_storage::user;
+
+  #[test]
+  fn session_must_expire_when_idle() {
+    let user = user("alice");
+    let now = Utc::now();
+    let mut session = Session::new(user, Duration::minutes(10), now);
+    assert_eq!(session.id.len(), 22);
+    assert!(session.touch(now + Duration::minutes(9)));
+    assert!(session.touch(now + Duration::minutes(18)));
+    assert!(!session.touch(now + Duration::minutes(29)));
+  }
+}
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use log::{debug, error, info, trace, warn};
+use serde::de::DeserializeOwned;
+use serde::{Serialize, Deserialize};
+use crate::course::Course;
+use crate::encryption::{ConversionError, decrypt_to_string, is_container, read_b64_from_file, vec_to_nonce};
+use crate::grade::{Grade, StoredGrade};
+use crate::keystore;
+use crate::login_throttle::LoginThrottle;
+use crate::persistence::{backup_path, write_atomically};
+use crate::user::User;
+use super::{migrate_grades, open_grades, open_value, seal_grades, seal_value, Storage};
+
+const DATABASE_FILE: &str = "grades_db.json";
+const USERS_DATABASE_FILE: &str = "usr_db.json";
+const COURSES_DATABASE_FILE: &str = "courses_db.json";
+const LOGIN_THROTTLE_FILE: &str = "login_throttle.json";
+/// Version of the single data key used before the key ring
+const LEGACY_KEY_ID: u32 = 1;
+// Nonce files used before the nonce was stored in the encrypted container
+const GRADES_NONCE_FILE: &str = "nonce.txt";
+const USERS_NONCE_FILE: &str = "usr_nonce.txt";
+const COURSES_NONCE_FILE: &str = "courses_nonce.txt";
+
+/// Grades of a student in the grades database. They are sealed with the
+/// record key of the student, older versions stored them in clear inside
+/// the encrypted database.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum StudentGrades {
+  Sealed(String),
+  Clear(Vec<StoredGrade>),
+}
+
+/// Each database is an encrypted JSON file in the database directory
+/// (`db/`), the previous generation is kept next to it as `.bak`.
+pub struct JsonStorage {
+  db_dir: PathBuf,
+  /// Directory of the nonce files of the legacy format
+  secret_dir: PathBuf,
+  /// Databases in plaintext are only migrated when no data key existed
+  /// before, otherwise they replaced encrypted ones
+  plaintext_allowed: bool,
+}
+
+impl JsonStorage {
+  pub fn new(db_dir: &Path, secret_dir: &Path) -> JsonStorage {
+    let plaintext_allowed = keystore::is_first_start().unwrap_or_else(|e| {
+      debug!("{}", e);
+      false
+    });
+    JsonStorage { db_dir: db_dir.to_path_buf(), secret_dir: secret_dir.to_path_buf(), plaintext_allowed }
+  }
+
+  fn db_file(&self, name: &str) -> String {
+    self.db_dir.join(name).to_string_lossy().into_owned()
+  }
+
+  fn nonce_file(&self, name: &str) -> String {
+    self.secret_dir.join(name).to_string_lossy().into_owned()
+  }
+}
+
+impl Storage for JsonStorage {
+  fn load_users(&self) -> Result<HashMap<String, User>, Box<dyn Error>> {
+    read_db_or_backup(&self.db_file(USERS_DATABASE_FILE), Some(&self.nonce_file(USERS_NONCE_FILE)), self.plaintext_allowed)
+  }
+
+  fn save_users(&mut self, users: &HashMap<String, User>) -> Result<(), Box<dyn Error>> {
+    write_encrypted_db(&self.db_file(USERS_DATABASE_FILE), Some(&self.nonce_file(USERS_NONCE_FILE)), users)
+  }
+
+  fn load_grades(&self) -> Result<HashMap<String, Vec<Grade>>, Box<dyn Error>> {
+    let stored: HashMap<String, StudentGrades> = read_db_or_backup(&self.db_file(DATABASE_FILE), Some(&self.nonce_file(GRADES_NONCE_FILE)), self.plaintext_allowed)?;
+    let mut map = HashMap::new();
+    for (student, grades) in stored {
+      let grades = match grades {
+        StudentGrades::Clear(grades) => grades,
+        StudentGrades::Sealed(sealed) => match open_grades(&student, &sealed)? {
+          Some(grades) => grades,
+          None => {
+            info!("Grades of {} were erased, skipping them.", student);
+            continue;
+          }
+        },
+      };
+      map.insert(student, grades);
+    }
+    Ok(migrate_grades(map))
+  }
+
+  fn save_grades(&mut self, grades: &HashMap<String, Vec<Grade>>) -> Result<(), Box<dyn Error>> {
+    let mut sealed = HashMap::new();
+    for (student, grades) in grades.iter() {
+      sealed.insert(student.clone(), StudentGrades::Sealed(seal_grades(student, grades)?));
+    }
+    write_encrypted_db(&self.db_file(DATABASE_FILE), Some(&self.nonce_file(GRADES_NONCE_FILE)), &sealed)
+  }
+
+  fn load_courses(&self) -> Result<HashMap<String, Course>, Box<dyn Error>> {
+    read_db_or_backup(&self.db_file(COURSES_DATABASE_FILE), Some(&self.nonce_file(COURSES_NONCE_FILE)), self.plaintext_allowed)
+  }
+
+  fn save_courses(&mut self, courses: &HashMap<String, Course>) -> Result<(), Box<dyn Error>> {
+    write_encrypted_db(&self.db_file(COURSES_DATABASE_FILE), Some(&self.nonce_file(COURSES_NONCE_FILE)), courses)
+  }
+
+  fn load_login_throttle(&self) -> Result<LoginThrottle, Box<dyn Error>> {
+    read_db_or_backup(&self.db_file(LOGIN_THROTTLE_FILE), None, self.plaintext_allowed)
+  }
+
+  fn save_login_throttle(&mut self, throttle: &LoginThrottle) -> Result<(), Box<dyn Error>> {
+    write_encrypted_db(&self.db_file(LOGIN_THROTTLE_FILE), None, throttle)
+  }
+}
+
+/// Encrypt the serialized value in a container and atomically replace path.
+/// The nonce file used by older versions is then obsolete.
+fn write_encrypted_db<T: Serialize>(path: &str, legacy_nonce_path: Option<&str>, value: &T) -> Result<(), Box<dyn Error>> {
+  let container = seal_value(value)?;
+  write_atomically(path, &container, true)?;
+  if let Some(nonce_path) = legacy_nonce_path {
+    if fs::remove_file(nonce_path).is_ok() {
+      info!("Removed obsolete nonce file {}.", nonce_path);
+    }
+  }
+  Ok(())
+}
+
+/// Read a database, falling back to its previous generation if the file
+/// is missing or corrupted. Return an empty database if neither exists.
+fn read_db_or_backup<T: DeserializeOwned + Default>(path: &str, legacy_nonce_path: Option<&str>, plaintext_allowed: bool) -> Result<T, Box<dyn Error>> {
+  let backup = backup_path(path);
+  let has_backup = Path::new(&backup).exists();
+  if Path::new(path).exists() {
+    match read_db(path, legacy_nonce_path, plaintext_allowed) {
+      Ok(val) => return Ok(val),
+      Err(e) if !has_backup => return Err(e),
+      Err(e) => {
+        debug!("{}", e);
+        error!("{} is corrupted, loading the previous generation {}.", path, backup);
+      }
+    }
+  } else if has_backup {
+    warn!("{} is missing, loading the previous generation {}.", path, backup);
+  } else {
+    return Ok(T::default());
+  }
+  read_db(&backup, legacy_nonce_path, plaintext_allowed)
+}
+
+/// Read an encrypted database. Databases written with a separate nonce
+/// file by older versions are still accepted, and in plaintext on the first
+/// start only. They are converted on the next save.
+fn read_db<T: DeserializeOwned>(path: &str, legacy_nonce_path: Option<&str>, plaintext_allowed: bool) -> Result<T, Box<dyn Error>> {
+  let mut content = vec![];
+  File::open(path)?.read_to_end(&mut content)?;
+  if is_container(&content) {
+    return open_value(&content).map_err(|e| {
+      error!("Cannot decrypt {} : {}", path, e);
+      e
+    });
+  }
+  if let Ok(map) = serde_json::from_slice(&content) {
+    if !plaintext_allowed {
+      error!("{} is stored in plaintext although the data is encrypted, refusing it.", path);
+      return Err(format!("{} is not encrypted", path).into());
+    }
+    warn!("{} is stored in plaintext, it will be encrypted on next save.", path);
+    return Ok(map);
+  }
+  let nonce_path = legacy_nonce_path.ok_or(ConversionError)?;
+  let clear = read_legacy_encrypted(path, &content, nonce_path)?;
+  let map = serde_json::from_str(clear.as_str()).map_err(|e| {
+    error!("{} : Cannot deserialize decrypted {}.", e, path);
+    e
+  })?;
+  Ok(map)
+}
+
+/// Decrypt a file written before the container format, the nonce was then
+/// stored in its own file.
+fn read_legacy_encrypted(path: &str, content: &[u8], nonce_path: &str) -> Result<String, Box<dyn Error>> {
+  trace!("{} is not a container, trying legacy decryption ...", path);
+  let key = keystore::key_by_id(LEGACY_KEY_ID)
+    .map_err(|e| {
+      debug!("{}", e);
+      error!("Cannot decrypt {}, key not available.", path);
+      e
+    })?;
+  let nonce = read_b64_from_file(nonce_path)
+    .map_err(|e| {
+      debug!("{}", e);
+      error!("Cannot decrypt {}, nonce not found.", path);
+      e
+    })?;
+  let nonce = vec_to_nonce(nonce).ok_or(ConversionError)?;
+  let clear = decrypt_to_string(content, &key, &nonce)?;
+  warn!("{} uses the legacy encryption format, it will be converted on next save.", path);
+  Ok(clear)
+}
+
+#[cfg(test)]
+mod test_json_storage {
+  use super::*;
+  use super::super::test_storage::user;
+
+  #[test]
+  fn corrupted_database_must_fall_back_to_backup() {
+    let dir = std::env::temp_dir().join(format!("king-json-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("db.json").to_string_lossy().into_owned();
+    let missing: HashMap<String, u32> = read_db_or_backup(&path, None, true).unwrap();
+    assert!(missing.is_empty());
+    fs::write(backup_path(&path), "{\"alice\":5}").unwrap();
+    fs::write(&path, "garbage").unwrap();
+    let loaded: HashMap<String, u32> = read_db_or_backup(&path, None, true).unwrap();
+    assert_eq!(loaded.get("alice"), Some(&5));
+    fs::remove_file(&path).unwrap();
+    let loaded: HashMap<String, u32> = read_db_or_backup(&path, None, true).unwrap();
+    assert_eq!(loaded.get("alice"), Some(&5));
+    fs::write(backup_path(&path), "garbage").unwrap();
+    fs::write(&path, "garbage").unwrap();
+    assert!(read_db_or_backup::<HashMap<String, u32>>(&path, None, true).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn plaintext_must_be_rejected_once_encrypted() {
+    let dir = std::env::temp_dir().join(format!("king-json-plain-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let mut storage = JsonStorage { plaintext_allowed: false, ..JsonStorage::new(&dir, &dir) };
+    let users = HashMap::from([("plainalice".to_string(), user("plainalice"))]);
+    storage.save_users(&users).unwrap();
+    storage.save_users(&users).unwrap();
+    let path = storage.db_file(USERS_DATABASE_FILE);
+    let forged = HashMap::from([("mallory".to_string(), user("mallory"))]);
+    fs::write(&path, serde_json::to_string(&forged).unwrap()).unwrap();
+    // The encrypted backup is loaded instead of the forged file
+    assert_eq!(storage.load_users().unwrap().keys().collect::<Vec<_>>(), vec!["plainalice"]);
+    fs::write(backup_path(&path), serde_json::to_string(&forged).unwrap()).unwrap();
+    assert!(storage.load_users().is_err());
+    // Before any data key existed, the plaintext is migrated
+    storage.plaintext_allowed = true;
+    assert!(storage.load_users().unwrap().contains_key("mallory"));
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+use base64::{Engine as _, engine::general_purpose};
+use log::info;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::config::{APP_CONFIG, StorageBackend};
+use crate::course::Course;
+use crate::encryption::{container_key_id, open_container, seal_container};
+use crate::grade::{assign_grade_ids, Grade, StoredGrade};
+use crate::keystore;
+use crate::login_throttle::LoginThrottle;
+use crate::user::User;
+
+mod json;
+mod sqlite;
+
+pub use json::JsonStorage;
+pub use sqlite::SqliteStorage;
+
+/// Persistence of the databases. Implementations are responsible for the
+/// encryption at rest: records are sealed with the master key, the grades
+/// of each student with their own record key.
+pub trait Storage: Send {
+  fn load_users(&self) -> Result<HashMap<String, User>, Box<dyn Error>>;
+  fn save_users(&mut self, users: &HashMap<String, User>) -> Result<(), Box<dyn Error>>;
+  fn load_grades(&self) -> Result<HashMap<String, Vec<Grade>>, Box<dyn Error>>;
+  fn save_grades(&mut self, grades: &HashMap<String, Vec<Grade>>) -> Result<(), Box<dyn Error>>;
+  fn load_courses(&self) -> Result<HashMap<String, Course>, Box<dyn Error>>;
+  fn save_courses(&mut self, courses: &HashMap<String, Course>) -> Result<(), Box<dyn Error>>;
+  /// Failed logins of all the users, kept with the users
+  fn load_login_throttle(&self) -> Result<LoginThrottle, Box<dyn Error>>;
+  fn save_login_throttle(&mut self, throttle: &LoginThrottle) -> Result<(), Box<dyn Error>>;
+}
+
+/// Open the storage backend selected in the configuration
+pub fn open_storage() -> Result<Box<dyn Storage>, Box<dyn Error>> {
+  match APP_CONFIG.storage {
+    StorageBackend::Json => Ok(Box::new(JsonStorage::new(Path::new("db"), Path::new(&APP_CONFIG.key_dir)))),
+    StorageBackend::Sqlite => Ok(Box::new(SqliteStorage::open(&APP_CONFIG.sqlite_path)?)),
+  }
+}
+
+/// Serialize the value and encrypt it with the current master key
+fn seal_value<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, Box<dyn Error>> {
+  let json = serde_json::to_string(value)?;
+  let (key_id, key) = keystore::current_key()?;
+  seal_container(&json, key_id, &key)
+}
+
+/// Decrypt a value sealed by `seal_value`
+fn open_value<T: DeserializeOwned>(container: &[u8]) -> Result<T, Box<dyn Error>> {
+  let key = keystore::key_by_id(container_key_id(container)?)?;
+  let json = open_container(container, &key)?;
+  Ok(serde_json::from_str(&json)?)
+}
+
+/// Encrypt the grades of a student with their record key
+fn seal_grades(student_name: &str, grades: &[Grade]) -> Result<String, Box<dyn Error>> {
+  let json = serde_json::to_string(grades)?;
+  let (version, key) = keystore::record_key(student_name)?;
+  let container = seal_container(&json, version, &key)?;
+  Ok(general_purpose::STANDARD_NO_PAD.encode(container))
+}
+
+/// Decrypt the grades of a student, None if they were erased. A missing
+/// record key fails, skipping the student would lose their grades on the
+/// next save.
+fn open_grades(student_name: &str, sealed: &str) -> Result<Option<Vec<StoredGrade>>, Box<dyn Error>> {
+  let container = general_purpose::STANDARD_NO_PAD.decode(sealed)?;
+  let version = container_key_id(&container)?;
+  match keystore::record_key_by_id(student_name, version)? {
+    Some(key) => Ok(Some(serde_json::from_str(&open_container(&container, &key)?)?)),
+    None => Ok(None),
+  }
+}
+
+/// Convert the stored grades to records. Bare values written by older
+/// versions become legacy untagged grades, they are saved as records
+/// on the next save. Grades saved without an id get one.
+fn migrate_grades(stored: HashMap<String, Vec<StoredGrade>>) -> HashMap<String, Vec<Grade>> {
+  stored.into_iter()
+    .map(|(student, grades)| {
+      let mut grades: Vec<Grade> = grades.into_iter().map(Grade::from).collect();
+      assign_grade_ids(&mut grades);
+      let legacy = grades.iter().filter(|g| g.is_legacy()).count();
+      if legacy > 0 {
+        info!("Migrated {} legacy grades of {}.", legacy, student);
+      }
+      (student, grades)
+    })
+    .collect()
+}
+
+#[cfg(test)]
+pub mod test_storage {
+  use std::fs;
+  use crate::user::Role;
+  use super::*;
+
+  /// Student without a usable password
+  pub fn user(name: &str) -> User {
+    User::new(name, "hash".to_string(), Role::STUDENT)
+  }
+
+  fn course(id: &str) -> Course {
+    Course { published: true, ..Course::new(id, "Title", &["prof1"], &["storealice"]) }
+  }
+
+  /// Save every database and read it back
+  fn assert_round_trip(storage: &mut dyn Storage) {
+    let users = HashMap::from([("storealice".to_string(), user("storealice"))]);
+    let grades = HashMap::from([("storealice".to_string(), vec![Grade { id: 1, ..Grade::legacy(5.5) }])]);
+    let courses = HashMap::from([("SLH".to_string(), course("SLH"))]);
+    let throttle = LoginThrottle { failures: 3, ..LoginThrottle::default() };
+    storage.save_users(&users).unwrap();
+    storage.save_grades(&grades).unwrap();
+    storage.save_courses(&courses).unwrap();
+    storage.save_login_throttle(&throttle).unwrap();
+    let loaded = storage.load_users().unwrap();
+    assert_eq!(loaded.keys().collect::<Vec<_>>(), vec!["storealice"]);
+    assert_eq!(loaded["storealice"].pwd_hash, "hash");
+    assert_eq!(storage.load_grades().unwrap(), grades);
+    assert_eq!(storage.load_courses().unwrap(), courses);
+    assert_eq!(storage.load_login_throttle().unwrap(), throttle);
+  }
+
+  #[test]
+  fn json_storage_must_round_trip() {
+    let dir = std::env::temp_dir().join(format!("king-storage-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    assert_round_trip(&mut JsonStorage::new(&dir, &dir));
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn sqlite_storage_must_round_trip() {
+    assert_round_trip(&mut SqliteStorage::open(":memory:").unwrap());
+  }
+}
@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Write as _;
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use rusqlite::{Connection, Params, params};
+use serde::de::DeserializeOwned;
+use serde::{Serialize, Deserialize};
+use sha2::Sha256;
+use crate::course::Course;
+use crate::encryption::{container_key_id, is_container};
+use crate::grade::Grade;
+use crate::keystore;
+use crate::login_throttle::LoginThrottle;
+use crate::user::User;
+use super::{migrate_grades, open_grades, open_value, seal_grades, seal_value, Storage};
+
+const SCHEMA: &str = "
+  CREATE TABLE IF NOT EXISTS users (name TEXT PRIMARY KEY, record BLOB NOT NULL);
+  CREATE TABLE IF NOT EXISTS grades (student TEXT PRIMARY KEY, record BLOB NOT NULL);
+  CREATE TABLE IF NOT EXISTS courses (id TEXT PRIMARY KEY, record BLOB NOT NULL);
+  CREATE TABLE IF NOT EXISTS state (key TEXT PRIMARY KEY, record BLOB NOT NULL);
+";
+
+/// Key of a row and its sealed record
+type Row = (String, Vec<u8>);
+
+/// Key of the global login throttle in the state table
+const LOGIN_THROTTLE_KEY: &str = "login_throttle";
+/// Context of the row key derived from a master key
+const ROW_KEY_CONTEXT: &[u8] = b"KING row keys";
+
+/// Grades of a student in the grades table, sealed with the master key so
+/// that the name of the student is not stored in clear. The grades inside
+/// are sealed with the record key of the student.
+#[derive(Serialize, Deserialize)]
+struct GradesRecord {
+  student: String,
+  grades: String,
+}
+
+/// Embedded SQLite database, one row per user, student and course.
+/// Rows hold the same sealed records as the JSON files, under a keyed hash
+/// of the user name or course id.
+pub struct SqliteStorage {
+  conn: Connection,
+}
+
+/// Keyed hash of a name under a key derived from the given master key
+fn row_key(key_id: u32, name: &str) -> Result<String, Box<dyn Error>> {
+  let mut mac = Hmac::<Sha256>::new_from_slice(&keystore::derived_key(key_id, ROW_KEY_CONTEXT)?)?;
+  mac.update(name.as_bytes());
+  Ok(mac.finalize().into_bytes().iter().fold(String::new(), |mut out, b| {
+    let _ = write!(out, "{:02x}", b);
+    out
+  }))
+}
+
+/// Seal a record with the current master key, the row key is derived from
+/// the same master key.
+fn seal_row<T: Serialize>(name: &str, value: &T) -> Result<Row, Box<dyn Error>> {
+  let record = seal_value(value)?;
+  Ok((row_key(container_key_id(&record)?, name)?, record))
+}
+
+/// Open a row and check that it holds the record of its key, so that
+/// records cannot be moved between rows. Rows written before the keyed
+/// hashes have the name in clear, they are rewritten on the next save.
+fn open_row<T: DeserializeOwned>(row: &Row, name_of: impl Fn(&T) -> &str) -> Result<T, Box<dyn Error>> {
+  let (key, record) = row;
+  let value: T = open_value(record)?;
+  let name = name_of(&value);
+  if *key == row_key(container_key_id(record)?, name)? {
+    return Ok(value);
+  }
+  if key == name {
+    warn!("Row of {} stored under its name in clear, it will be hashed on next save.", name);
+    return Ok(value);
+  }
+  Err(format!("row {} does not hold its own record", key).into())
+}
+
+impl SqliteStorage {
+  pub fn open(path: &str) -> Result<SqliteStorage, Box<dyn Error>> {
+    let conn = Connection::open(path)?;
+    conn.execute_batch(SCHEMA)?;
+    info!("SQLite storage opened at {}.", path);
+    Ok(SqliteStorage { conn })
+  }
+
+  /// Read all the (key, sealed record) rows of a query
+  fn read_rows<P: Params>(&self, sql: &str, params: P) -> Result<Vec<Row>, Box<dyn Error>> {
+    let mut stmt = self.conn.prepare(sql)?;
+    let rows = stmt.query_map(params, |row| Ok((row.get(0)?, row.get(1)?)))?
+      .collect::<Result<Vec<Row>, _>>()?;
+    Ok(rows)
+  }
+
+  /// Replace the content of a table in a single transaction
+  fn replace_rows(&mut self, table: &str, rows: Vec<Row>) -> Result<(), Box<dyn Error>> {
+    let tx = self.conn.transaction()?;
+    tx.execute(&format!("DELETE FROM {}", table), [])?;
+    {
+      let mut stmt = tx.prepare(&format!("INSERT INTO {} VALUES (?1, ?2)", table))?;
+      for (key, record) in rows {
+        stmt.execute(params![key, record])?;
+      }
+    }
+    tx.commit()?;
+    Ok(())
+  }
+}
+
+impl Storage for SqliteStorage {
+  fn load_users(&self) -> Result<HashMap<String, User>, Box<dyn Error>> {
+    let mut map = HashMap::new();
+    for row in self.read_rows("SELECT name, record FROM users", [])? {
+      let user: User = open_row(&row, |u: &User| &u.name)?;
+      map.insert(user.name.clone(), user);
+    }
+    Ok(map)
+  }
+
+  fn save_users(&mut self, users: &HashMap<String, User>) -> Result<(), Box<dyn Error>> {
+    let mut rows = vec![];
+    for (name, user) in users {
+      rows.push(seal_row(name, user)?);
+    }
+    self.replace_rows("users", rows)
+  }
+
+  fn load_grades(&self) -> Result<HashMap<String, Vec<Grade>>, Box<dyn Error>> {
+    let mut map = HashMap::new();
+    for row in self.read_rows("SELECT student, record FROM grades", [])? {
+      // Rows written before the names were hashed hold the grades sealed
+      // with the record key only
+      let record = if is_container(&row.1) {
+        open_row(&row, |r: &GradesRecord| &r.student)?
+      } else {
+        GradesRecord { student: row.0, grades: String::from_utf8(row.1)? }
+      };
+      match open_grades(&record.student, &record.grades)? {
+        Some(grades) => {
+          map.insert(record.student, grades);
+        }
+        None => info!("Grades of {} were erased, skipping them.", record.student),
+      }
+    }
+    Ok(migrate_grades(map))
+  }
+
+  fn save_grades(&mut self, grades: &HashMap<String, Vec<Grade>>) -> Result<(), Box<dyn Error>> {
+    let mut rows = vec![];
+    for (student, grades) in grades {
+      let record = GradesRecord { student: student.clone(), grades: seal_grades(student, grades)? };
+      rows.push(seal_row(student, &record)?);
+    }
+    self.replace_rows("grades", rows)
+  }
+
+  fn load_courses(&self) -> Result<HashMap<String, Course>, Box<dyn Error>> {
+    let mut map = HashMap::new();
+    for row in self.read_rows("SELECT id, record FROM courses", [])? {
+      let course: Course = open_row(&row, |c: &Course| &c.id)?;
+      map.insert(course.id.clone(), course);
+    }
+    Ok(map)
+  }
+
+  fn save_courses(&mut self, courses: &HashMap<String, Course>) -> Result<(), Box<dyn Error>> {
+    let mut rows = vec![];
+    for (id, course) in courses {
+      rows.push(seal_row(id, course)?);
+    }
+    self.replace_rows("courses", rows)
+  }
+
+  fn load_login_throttle(&self) -> Result<LoginThrottle, Box<dyn Error>> {
+    let rows = self.read_rows("SELECT key, record FROM state WHERE key = ?1", params![LOGIN_THROTTLE_KEY])?;
+    match rows.first() {
+      Some((_, record)) => open_value(record),
+      None => Ok(LoginThrottle::default()),
+    }
+  }
+
+  fn save_login_throttle(&mut self, throttle: &LoginThrottle) -> Result<(), Box<dyn Error>> {
+    self.conn.execute(
+      "INSERT OR REPLACE INTO state VALUES (?1, ?2)",
+      params![LOGIN_THROTTLE_KEY, seal_value(throttle)?],
+    )?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test_sqlite {
+  use super::*;
+  use super::super::test_storage::user;
+
+  #[test]
+  fn rows_must_be_keyed_by_hashed_names() {
+    let mut storage = SqliteStorage::open(":memory:").unwrap();
+    let users: HashMap<String, User> = ["sqlalice", "sqlbob"].iter().map(|n| (n.to_string(), user(n))).collect();
+    storage.save_users(&users).unwrap();
+    let rows = storage.read_rows("SELECT name, record FROM users", []).unwrap();
+    assert!(rows.iter().all(|(key, _)| !users.contains_key(key)));
+    assert_eq!(storage.load_users().unwrap().len(), 2);
+    // The record of a user moved under the key of another one is rejected
+    storage.conn.execute("UPDATE users SET record = ?1 WHERE name = ?2", params![rows[0].1, rows[1].0]).unwrap();
+    assert!(storage.load_users().is_err());
+  }
+}
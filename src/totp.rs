@@ -0,0 +1,228 @@
+use std::error::Error;
+use std::fmt;
+use base64::{Engine as _, engine::general_purpose};
+use hmac::{Hmac, Mac};
+use lazy_static::__Deref;
+use log::{info, warn};
+use rand_core::{OsRng, RngCore};
+use serde::{Serialize, Deserialize};
+use sha1::Sha1;
+use crate::audit;
+use crate::audit::Outcome;
+use crate::db::USERS_DATABASE;
+use crate::encryption::{container_key_id, open_container, seal_container};
+use crate::hashing::{compare_pwd_with_hash, new_hash_from_pwd};
+use crate::keystore;
+
+const ISSUER: &str = "KING";
+const SECRET_LEN: usize = 20;
+const DIGITS: u32 = 6;
+const PERIOD: u64 = 30;
+/// Accepted clock drift, in periods, on each side
+const SKEW: u64 = 1;
+const RECOVERY_CODES: usize = 8;
+const RECOVERY_CODE_LEN: usize = 10;
+const BASE32: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// TOTP enrollment of a user. The secret is sealed with the master key,
+/// the recovery codes are Argon2 hashes and can be used once.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TotpEnrollment {
+  secret: String,
+  recovery_codes: Vec<String>,
+  /// Last period a code was accepted for, codes cannot be replayed
+  last_step: u64,
+}
+
+/// Secret generated for a user who has not confirmed it yet
+pub struct PendingEnrollment {
+  secret: [u8; SECRET_LEN],
+  pub uri: String,
+}
+
+#[derive(Debug)]
+pub enum TotpError {
+  UnknownUser,
+  AlreadyEnrolled,
+  NotEnrolled,
+  InvalidCode,
+}
+
+impl fmt::Display for TotpError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      TotpError::UnknownUser => write!(f, "Unknown user"),
+      TotpError::AlreadyEnrolled => write!(f, "Two-factor authentication is already enabled"),
+      TotpError::NotEnrolled => write!(f, "Two-factor authentication is not enabled"),
+      TotpError::InvalidCode => write!(f, "Invalid authentication code"),
+    }
+  }
+}
+
+impl Error for TotpError {}
+
+/// RFC 4648 base32 without padding, as expected by authenticator apps
+fn base32_encode(data: &[u8]) -> String {
+  let mut out = String::new();
+  for chunk in data.chunks(5) {
+    let mut buf = [0u8; 5];
+    buf[..chunk.len()].copy_from_slice(chunk);
+    let bits = buf.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+    let chars = (chunk.len() * 8 + 4) / 5;
+    for i in 0..chars {
+      out.push(BASE32[((bits >> (35 - i * 5)) & 0x1f) as usize] as char);
+    }
+  }
+  out
+}
+
+/// RFC 4226 one-time password
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+  let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts any key length");
+  mac.update(&counter.to_be_bytes());
+  let digest = mac.finalize().into_bytes();
+  let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+  let code = u32::from_be_bytes([digest[offset], digest[offset + 1], digest[offset + 2], digest[offset + 3]]) & 0x7fff_ffff;
+  code % 10u32.pow(DIGITS)
+}
+
+/// Return the period of the code if it is valid at the given time
+fn verify_code(secret: &[u8], code: &str, unix_time: u64) -> Option<u64> {
+  let code: u32 = code.trim().parse().ok()?;
+  let step = unix_time / PERIOD;
+  (step.saturating_sub(SKEW)..=step + SKEW).find(|s| hotp(secret, *s) == code)
+}
+
+fn unix_now() -> u64 {
+  chrono::Utc::now().timestamp() as u64
+}
+
+fn seal_secret(secret: &[u8]) -> Result<String, Box<dyn Error>> {
+  let (key_id, key) = keystore::current_key()?;
+  let container = seal_container(&general_purpose::STANDARD_NO_PAD.encode(secret), key_id, &key)?;
+  Ok(general_purpose::STANDARD_NO_PAD.encode(container))
+}
+
+fn open_secret(sealed: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+  let container = general_purpose::STANDARD_NO_PAD.decode(sealed)?;
+  let key = keystore::key_by_id(container_key_id(&container)?)?;
+  Ok(general_purpose::STANDARD_NO_PAD.decode(open_container(&container, &key)?)?)
+}
+
+fn generate_recovery_code() -> String {
+  let mut bytes = [0u8; RECOVERY_CODE_LEN];
+  OsRng.fill_bytes(&mut bytes);
+  bytes.iter().map(|b| BASE32[(*b & 0x1f) as usize] as char).collect()
+}
+
+pub fn is_enrolled(username: &str) -> bool {
+  USERS_DATABASE.deref().lock().unwrap()
+    .get(username)
+    .map_or(false, |u| u.totp.is_some())
+}
+
+/// Generate a secret for a user, to be confirmed with a first code
+pub fn start_enrollment(username: &str) -> Result<PendingEnrollment, TotpError> {
+  if is_enrolled(username) {
+    return Err(TotpError::AlreadyEnrolled);
+  }
+  let mut secret = [0u8; SECRET_LEN];
+  OsRng.fill_bytes(&mut secret);
+  let uri = format!(
+    "otpauth://totp/{issuer}:{user}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+    issuer = ISSUER, user = username, secret = base32_encode(&secret), digits = DIGITS, period = PERIOD,
+  );
+  Ok(PendingEnrollment { secret, uri })
+}
+
+/// Enable TOTP once the user proved their app generates valid codes.
+/// Return the recovery codes, they are shown only once.
+pub fn confirm_enrollment(username: &str, pending: &PendingEnrollment, code: &str) -> Result<Vec<String>, Box<dyn Error>> {
+  let step = verify_code(&pending.secret, code, unix_now()).ok_or(TotpError::InvalidCode)?;
+  let codes: Vec<String> = (0..RECOVERY_CODES).map(|_| generate_recovery_code()).collect();
+  let mut hashes = vec![];
+  for code in codes.iter() {
+    hashes.push(new_hash_from_pwd(code)?);
+  }
+  let enrollment = TotpEnrollment {
+    secret: seal_secret(&pending.secret)?,
+    recovery_codes: hashes,
+    last_step: step,
+  };
+  let mut db = USERS_DATABASE.deref().lock().unwrap();
+  let user = db.get_mut(username).ok_or(TotpError::UnknownUser)?;
+  user.totp = Some(enrollment);
+  info!("{} enabled two-factor authentication.", username);
+  audit::record(username, "totp.enable", username, Outcome::Success);
+  Ok(codes)
+}
+
+/// Check the second factor of an enrolled user, either a TOTP code or an
+/// unused recovery code.
+pub fn verify_second_factor(username: &str, code: &str) -> Result<(), Box<dyn Error>> {
+  let mut db = USERS_DATABASE.deref().lock().unwrap();
+  let user = db.get_mut(username).ok_or(TotpError::UnknownUser)?;
+  let enrollment = user.totp.as_mut().ok_or(TotpError::NotEnrolled)?;
+  let secret = open_secret(&enrollment.secret)?;
+  if let Some(step) = verify_code(&secret, code, unix_now()) {
+    if step <= enrollment.last_step {
+      warn!("Replayed authentication code for {}.", username);
+      audit::record(username, "totp.replay", username, Outcome::Denied);
+      return Err(TotpError::InvalidCode.into());
+    }
+    enrollment.last_step = step;
+    return Ok(());
+  }
+  let code = code.trim().to_uppercase();
+  if let Some(i) = enrollment.recovery_codes.iter().position(|h| compare_pwd_with_hash(&code, h)) {
+    enrollment.recovery_codes.remove(i);
+    warn!("{} used a recovery code, {} left.", username, enrollment.recovery_codes.len());
+    audit::record(username, "totp.recovery_code", username, Outcome::Success);
+    return Ok(());
+  }
+  Err(TotpError::InvalidCode.into())
+}
+
+/// Disable TOTP after checking a current code
+pub fn disable(username: &str, code: &str) -> Result<(), Box<dyn Error>> {
+  verify_second_factor(username, code)?;
+  let mut db = USERS_DATABASE.deref().lock().unwrap();
+  let user = db.get_mut(username).ok_or(TotpError::UnknownUser)?;
+  user.totp = None;
+  info!("{} disabled two-factor authentication.", username);
+  audit::record(username, "totp.disable", username, Outcome::Success);
+  Ok(())
+}
+
+#[cfg(test)]
+mod test_totp {
+  use super::*;
+
+  const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+  #[test]
+  fn hotp_must_match_rfc_test_vectors() {
+    // RFC 4226 appendix D, 6 digits
+    assert_eq!(hotp(RFC_SECRET, 0), 755224);
+    assert_eq!(hotp(RFC_SECRET, 9), 520489);
+    // RFC 6238 appendix B, SHA1, last 6 digits
+    assert_eq!(hotp(RFC_SECRET, 59 / PERIOD), 287082);
+    assert_eq!(hotp(RFC_SECRET, 1111111109 / PERIOD), 81804);
+  }
+
+  #[test]
+  fn code_must_be_accepted_within_skew_only() {
+    let now = 1111111109;
+    let code = format!("{:06}", hotp(RFC_SECRET, now / PERIOD));
+    assert_eq!(verify_code(RFC_SECRET, &code, now), Some(now / PERIOD));
+    assert_eq!(verify_code(RFC_SECRET, &code, now + PERIOD), Some(now / PERIOD));
+    assert_eq!(verify_code(RFC_SECRET, &code, now + 3 * PERIOD), None);
+    assert_eq!(verify_code(RFC_SECRET, "abc", now), None);
+  }
+
+  #[test]
+  fn base32_must_follow_rfc_4648() {
+    assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI");
+    assert_eq!(base32_encode(RFC_SECRET), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+  }
+}
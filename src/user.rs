@@ -1,10 +1,14 @@
 use std::fmt::{Display, Formatter};
+use chrono::{DateTime, Utc};
+use crate::login_throttle::LoginThrottle;
+use crate::totp::TotpEnrollment;
 use serde::{Serialize, Deserialize};
 
 #[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
 pub enum Role {
   STUDENT,
   PROF,
+  ADMIN,
   NONE,
 }
 
@@ -13,21 +17,40 @@ impl Display for Role {
     match self {
       Role::STUDENT => write!(f, "Student"),
       Role::PROF => write!(f, "Prof"),
+      Role::ADMIN => write!(f, "Admin"),
       Role::NONE => write!(f, "NONE"),
     }
   }
 }
 
+impl Role {
+  /// Whether a user name is taken by a role, whatever the case
+  pub fn is_role_name(name: &str) -> bool {
+    [Role::STUDENT, Role::PROF, Role::ADMIN, Role::NONE].iter().any(|r| r.to_string().eq_ignore_ascii_case(name))
+  }
+}
+
 #[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
 pub enum Resource {
   GRADES,
   USERS,
 }
 
+impl Display for Resource {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Resource::GRADES => write!(f, "grades"),
+      Resource::USERS => write!(f, "users"),
+    }
+  }
+}
+
 #[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
 pub enum Action {
   Write,
   Read,
+  /// Publish the grades of a course to its students
+  Publish,
   NONE,
 }
 
@@ -36,6 +59,7 @@ impl Display for Action {
     match self {
       Action::Write => write!(f, "Write"),
       Action::Read =>  write!(f, "Read"),
+      Action::Publish =>  write!(f, "Publish"),
       Action::NONE =>  write!(f, "NONE"),
     }
   }
@@ -45,5 +69,66 @@ impl Display for Action {
 pub struct User {
   pub name: String,
   pub pwd_hash: String,
-  pub role: Role
+  pub role: Role,
+  /// Disabled users cannot log in and get no permissions
+  #[serde(default)]
+  pub disabled: bool,
+  /// Last password change, None if the password was never changed
+  #[serde(default)]
+  pub pwd_changed_at: Option<DateTime<Utc>>,
+  /// Set when the password was chosen by someone else
+  #[serde(default)]
+  pub must_change_pwd: bool,
+  #[serde(default)]
+  pub pwd_expires_at: Option<DateTime<Utc>>,
+  #[serde(default)]
+  pub login_throttle: LoginThrottle,
+  /// Second factor, if the user enrolled
+  #[serde(default)]
+  pub totp: Option<TotpEnrollment>,
+}
+
+impl User {
+  /// Enabled user who never changed their password
+  pub fn new(name: &str, pwd_hash: String, role: Role) -> User {
+    User {
+      name: name.to_string(),
+      pwd_hash,
+      role,
+      disabled: false,
+      pwd_changed_at: None,
+      must_change_pwd: false,
+      pwd_expires_at: None,
+      login_throttle: LoginThrottle::default(),
+      totp: None,
+    }
+  }
+
+  /// Whether the user has to choose a new password before going further
+  pub fn password_change_required(&self, now: DateTime<Utc>) -> bool {
+    self.must_change_pwd
+      || self.pwd_changed_at.is_none()
+      || self.pwd_expires_at.map_or(false, |expiry| expiry <= now)
+  }
+}
+
+#[cfg(test)]
+mod test_user {
+  use chrono::Duration;
+  use crate::storage::test_storage::user;
+  use super::*;
+
+  #[test]
+  fn password_change_must_be_required_when_expired_or_never_changed() {
+    let now = Utc::now();
+    let mut user = user("alice");
+    assert!(user.password_change_required(now));
+    user.pwd_changed_at = Some(now - Duration::days(10));
+    assert!(!user.password_change_required(now));
+    user.pwd_expires_at = Some(now - Duration::days(1));
+    assert!(user.password_change_required(now));
+    user.pwd_expires_at = Some(now + Duration::days(1));
+    user.must_change_pwd = true;
+    assert!(user.password_change_required(now));
+  }
 }
@@ -0,0 +1,414 @@
+use std::collections::HashMap;
+use std::fmt;
+use chrono::{Duration, Utc};
+use lazy_static::__Deref;
+use log::{debug, error, info, warn};
+use crate::access_control::{ACCESS_CTRL, Explanation, Permission, RequestAttributes};
+use crate::audit;
+use crate::audit::Outcome;
+use crate::course::Course;
+use crate::db;
+use crate::db::USERS_DATABASE;
+use crate::config::APP_CONFIG;
+use crate::hashing::{compare_pwd_with_hash, needs_rehash, new_hash_from_pwd};
+use crate::password_policy::{check_password, PasswordPolicyError};
+use crate::login_throttle::LoginThrottle;
+use crate::user::{Action, Resource, Role, User};
+
+#[derive(Debug, PartialEq)]
+pub enum UserAdminError {
+  Unauthorized,
+  UnknownUser,
+  UserExists,
+  /// User names equal to a role name are refused
+  ReservedName,
+  /// Administrators cannot disable, delete or demote themselves
+  SelfModification,
+  WrongPassword,
+  WeakPassword(PasswordPolicyError),
+  Hashing,
+  Policy,
+  Erasure,
+}
+
+impl fmt::Display for UserAdminError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      UserAdminError::Unauthorized => write!(f, "Not allowed to manage users"),
+      UserAdminError::UnknownUser => write!(f, "Unknown user"),
+      UserAdminError::UserExists => write!(f, "User already exists"),
+      UserAdminError::ReservedName => write!(f, "The username is reserved"),
+      UserAdminError::SelfModification => write!(f, "Cannot modify its own account"),
+      UserAdminError::WrongPassword => write!(f, "Wrong current password"),
+      UserAdminError::WeakPassword(e) => write!(f, "Password refused, {}", e),
+      UserAdminError::Hashing => write!(f, "Cannot hash the password"),
+      UserAdminError::Policy => write!(f, "Cannot update the access control policies"),
+      UserAdminError::Erasure => write!(f, "Cannot erase the data of the user"),
+    }
+  }
+}
+
+impl std::error::Error for UserAdminError {}
+
+/// Run an operation and record its outcome in the audit log
+fn audited<T>(actor: &str, action: &str, target: &str, op: impl FnOnce() -> Result<T, UserAdminError>) -> Result<T, UserAdminError> {
+  let result = op();
+  let outcome = match result {
+    Ok(_) => Outcome::Success,
+    Err(UserAdminError::Unauthorized) => Outcome::Denied,
+    Err(_) => Outcome::Failure,
+  };
+  audit::record(actor, action, target, outcome);
+  result
+}
+
+fn check_admin(requester: &User, action: Action) -> Result<(), UserAdminError> {
+  let resource = Resource::USERS.to_string();
+  let attributes = RequestAttributes::unrestricted(Utc::now());
+  if ACCESS_CTRL.check_authorization(requester.name.as_str(), resource.as_str(), action.to_string().as_str(), &attributes) {
+    Ok(())
+  } else {
+    warn!("Unauthorized attempt to manage users by {}.", requester.name);
+    Err(UserAdminError::Unauthorized)
+  }
+}
+
+/// Change the users or the courses, the policies are updated so that the
+/// change takes effect immediately.
+fn update_directory<F>(change: F) -> Result<(), UserAdminError>
+  where F: FnOnce(&mut HashMap<String, User>, &mut HashMap<String, Course>) -> Result<(), UserAdminError> {
+  db::update_users_and_courses(change).map_err(|e| {
+    debug!("{}", e);
+    error!("Cannot update the policies.");
+    UserAdminError::Policy
+  })?
+}
+
+/// Apply a change to an existing user other than the requester
+fn modify_user<F: FnOnce(&mut User)>(requester: &User, username: &str, change: F) -> Result<(), UserAdminError> {
+  check_admin(requester, Action::Write)?;
+  if requester.name == username {
+    return Err(UserAdminError::SelfModification);
+  }
+  update_directory(|users, _| {
+    let user = users.get_mut(username).ok_or(UserAdminError::UnknownUser)?;
+    change(user);
+    Ok(())
+  })
+}
+
+pub fn list_users(requester: &User) -> Result<Vec<User>, UserAdminError> {
+  check_admin(requester, Action::Read)?;
+  let db = USERS_DATABASE.deref().lock().unwrap();
+  let mut users: Vec<User> = db.values().cloned().collect();
+  users.sort_by(|a, b| a.name.cmp(&b.name));
+  Ok(users)
+}
+
+/// Whether an enabled administrator exists
+pub fn has_admin() -> bool {
+  USERS_DATABASE.deref().lock().unwrap().values().any(|u| u.role == Role::ADMIN && !u.disabled)
+}
+
+/// Create the first administrator with the credentials chosen by whoever
+/// starts the application first. Refused once an administrator exists.
+pub fn create_first_admin(username: &str, password: &str) -> Result<(), UserAdminError> {
+  audited(username, "user.create", username, || {
+    if Role::is_role_name(username) {
+      return Err(UserAdminError::ReservedName);
+    }
+    check_password(username, password, &APP_CONFIG.password_policy).map_err(UserAdminError::WeakPassword)?;
+    let pwd_hash = new_hash_from_pwd(password).map_err(|_| UserAdminError::Hashing)?;
+    let now = Utc::now();
+    update_directory(|users, _| {
+      if users.values().any(|u| u.role == Role::ADMIN && !u.disabled) {
+        return Err(UserAdminError::Unauthorized);
+      }
+      if users.contains_key(username) {
+        return Err(UserAdminError::UserExists);
+      }
+      // The password was chosen by the administrator, no change is required
+      let admin = User {
+        pwd_changed_at: Some(now),
+        pwd_expires_at: APP_CONFIG.password_policy.max_age_days.map(|days| now + Duration::days(days)),
+        ..User::new(username, pwd_hash, Role::ADMIN)
+      };
+      users.insert(username.to_string(), admin);
+      Ok(())
+    })?;
+    info!("First administrator {} created.", username);
+    Ok(())
+  })
+}
+
+pub fn create_user(requester: &User, username: &str, password: &str, role: Role) -> Result<(), UserAdminError> {
+  audited(&requester.name, "user.create", username, || {
+    check_admin(requester, Action::Write)?;
+    if Role::is_role_name(username) {
+      return Err(UserAdminError::ReservedName);
+    }
+    check_password(username, password, &APP_CONFIG.password_policy).map_err(UserAdminError::WeakPassword)?;
+    let pwd_hash = new_hash_from_pwd(password).map_err(|_| UserAdminError::Hashing)?;
+    update_directory(|users, _| {
+      if users.contains_key(username) {
+        return Err(UserAdminError::UserExists);
+      }
+      users.insert(username.to_string(), User { must_change_pwd: true, ..User::new(username, pwd_hash, role) });
+      Ok(())
+    })?;
+    info!("{} created the user {} with role {}.", requester.name, username, role);
+    Ok(())
+  })
+}
+
+pub fn set_user_disabled(requester: &User, username: &str, disabled: bool) -> Result<(), UserAdminError> {
+  audited(&requester.name, if disabled { "user.disable" } else { "user.enable" }, username, || {
+    modify_user(requester, username, |u| {
+      u.disabled = disabled;
+      // Enabling an account also lifts a lockout
+      u.login_throttle = LoginThrottle::default();
+    })?;
+    info!("{} {} the user {}.", requester.name, if disabled { "disabled" } else { "enabled" }, username);
+    Ok(())
+  })
+}
+
+pub fn change_role(requester: &User, username: &str, role: Role) -> Result<(), UserAdminError> {
+  audited(&requester.name, "user.role", username, || {
+    modify_user(requester, username, |u| u.role = role)?;
+    info!("{} changed the role of {} to {}.", requester.name, username, role);
+    Ok(())
+  })
+}
+
+/// Delete a user with everything attached to their name: their grades and
+/// record keys, their place in the courses, their suspensions and
+/// delegations. A new account with the same name starts from nothing.
+/// The grades are erased for good, so only once the user and the policies
+/// are updated, and without holding the locks of the directory.
+pub fn delete_user(requester: &User, username: &str) -> Result<(), UserAdminError> {
+  audited(&requester.name, "user.delete", username, || {
+    check_admin(requester, Action::Write)?;
+    if requester.name == username {
+      return Err(UserAdminError::SelfModification);
+    }
+    update_directory(|users, courses| {
+      if !users.contains_key(username) {
+        return Err(UserAdminError::UnknownUser);
+      }
+      users.remove(username);
+      for course in courses.values_mut() {
+        course.teachers.retain(|t| t != username);
+        course.students.retain(|s| s != username);
+        course.suspensions.retain(|s| s.teacher != username);
+        course.delegations.retain(|d| d.delegator != username && d.delegate != username);
+      }
+      Ok(())
+    })?;
+    info!("{} deleted the user {}.", requester.name, username);
+    db::erase_student_grades(&requester.name, username).map_err(|e| {
+      debug!("{}", e);
+      error!("Cannot erase the grades of {}.", username);
+      UserAdminError::Erasure
+    })
+  })
+}
+
+/// Tell an administrator whether a subject may do an action on an object,
+/// and why.
+pub fn explain_authorization(requester: &User, subject: &str, resource: &str, action: Action) -> Result<Explanation, UserAdminError> {
+  audited(&requester.name, "policy.explain", subject, || {
+    check_admin(requester, Action::Read)?;
+    ACCESS_CTRL.explain(subject, resource, action.to_string().as_str(), &db::resource_attributes(resource)).map_err(|e| {
+      debug!("{}", e);
+      error!("Cannot evaluate the request of {}.", subject);
+      UserAdminError::Policy
+    })
+  })
+}
+
+/// Effective permissions of a user, for an administrator
+pub fn user_permissions(requester: &User, username: &str) -> Result<Vec<Permission>, UserAdminError> {
+  audited(&requester.name, "policy.permissions", username, || {
+    check_admin(requester, Action::Read)?;
+    if !USERS_DATABASE.deref().lock().unwrap().contains_key(username) {
+      return Err(UserAdminError::UnknownUser);
+    }
+    Ok(ACCESS_CTRL.permissions(username, db::resource_attributes))
+  })
+}
+
+/// Let a user replace their own password after checking the current one
+pub fn change_password(username: &str, current: &str, new: &str) -> Result<(), UserAdminError> {
+  audited(username, "password.change", username, || {
+    let pwd_hash = USERS_DATABASE.deref().lock().unwrap()
+      .get(username)
+      .map(|u| u.pwd_hash.clone())
+      .ok_or(UserAdminError::UnknownUser)?;
+    if !compare_pwd_with_hash(current, pwd_hash.as_str()) {
+      warn!("Password change of {} refused, wrong current password.", username);
+      return Err(UserAdminError::WrongPassword);
+    }
+    if new == current {
+      return Err(UserAdminError::WeakPassword(PasswordPolicyError::SameAsCurrent));
+    }
+    check_password(username, new, &APP_CONFIG.password_policy).map_err(UserAdminError::WeakPassword)?;
+    let pwd_hash = new_hash_from_pwd(new).map_err(|_| UserAdminError::Hashing)?;
+    let mut db = USERS_DATABASE.deref().lock().unwrap();
+    let user = db.get_mut(username).ok_or(UserAdminError::UnknownUser)?;
+    let now = Utc::now();
+    user.pwd_hash = pwd_hash;
+    user.pwd_changed_at = Some(now);
+    user.must_change_pwd = false;
+    user.pwd_expires_at = APP_CONFIG.password_policy.max_age_days.map(|days| now + Duration::days(days));
+    info!("{} changed their password.", username);
+    Ok(())
+  })
+}
+
+/// Hash again a password just verified at login if its hash was made with
+/// weaker parameters than the current ones.
+pub fn upgrade_password_hash(username: &str, password: &str) -> Result<(), UserAdminError> {
+  let mut db = USERS_DATABASE.deref().lock().unwrap();
+  let user = db.get_mut(username).ok_or(UserAdminError::UnknownUser)?;
+  if needs_rehash(&user.pwd_hash) {
+    user.pwd_hash = new_hash_from_pwd(password).map_err(|_| UserAdminError::Hashing)?;
+    info!("Password hash of {} upgraded to the current parameters.", username);
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod test_user_admin {
+  use crate::access_control::{POLICY, ROLE};
+  use crate::course::{Delegation, Suspension};
+  use crate::grade::Grade;
+  use crate::keystore;
+  use crate::storage::test_storage::user;
+  use super::*;
+
+  const PASSWORD: &str = "Correct1Horse";
+
+  /// Add a user directly to the database, without password
+  fn add_user(name: &str, role: Role) -> User {
+    let user = User { role, ..user(name) };
+    let added = user.clone();
+    db::update_users_and_courses(|users, _| users.insert(user.name.clone(), user)).unwrap();
+    added
+  }
+
+  fn get_user(name: &str) -> Option<User> {
+    USERS_DATABASE.deref().lock().unwrap().get(name).cloned()
+  }
+
+  fn has_rules(ptype: &str, subject: &str) -> bool {
+    ACCESS_CTRL.rules(ptype).iter().any(|r| r[0] == subject)
+  }
+
+  #[test]
+  fn only_admins_must_create_users() {
+    let admin = add_user("uacadmin", Role::ADMIN);
+    let student = add_user("uacstudent", Role::STUDENT);
+    assert_eq!(create_user(&student, "uacnew", PASSWORD, Role::STUDENT), Err(UserAdminError::Unauthorized));
+    assert!(matches!(create_user(&admin, "uacnew", "short", Role::STUDENT), Err(UserAdminError::WeakPassword(_))));
+    assert_eq!(create_user(&admin, "uacnew", PASSWORD, Role::PROF), Ok(()));
+    assert_eq!(create_user(&admin, "uacnew", PASSWORD, Role::PROF), Err(UserAdminError::UserExists));
+    let created = get_user("uacnew").unwrap();
+    assert!(created.must_change_pwd);
+    assert!(ACCESS_CTRL.rules(ROLE).contains(&vec!["uacnew".to_string(), "role:Prof".to_string()]));
+  }
+
+  #[test]
+  fn disabled_users_must_lose_their_rules() {
+    let admin = add_user("uadadmin", Role::ADMIN);
+    add_user("uadstudent", Role::STUDENT);
+    assert!(has_rules(POLICY, "uadstudent"));
+    assert_eq!(set_user_disabled(&admin, "uadadmin", true), Err(UserAdminError::SelfModification));
+    assert_eq!(set_user_disabled(&admin, "uadnobody", true), Err(UserAdminError::UnknownUser));
+    assert_eq!(set_user_disabled(&admin, "uadstudent", true), Ok(()));
+    assert!(get_user("uadstudent").unwrap().disabled);
+    assert!(!has_rules(POLICY, "uadstudent"));
+    assert_eq!(set_user_disabled(&admin, "uadstudent", false), Ok(()));
+    assert!(has_rules(POLICY, "uadstudent"));
+  }
+
+  #[test]
+  fn role_change_must_update_the_roles() {
+    let admin = add_user("uaradmin", Role::ADMIN);
+    add_user("uarstudent", Role::STUDENT);
+    assert!(!has_rules(ROLE, "uarstudent"));
+    assert_eq!(change_role(&admin, "uaradmin", Role::STUDENT), Err(UserAdminError::SelfModification));
+    assert_eq!(change_role(&admin, "uarstudent", Role::PROF), Ok(()));
+    assert_eq!(get_user("uarstudent").unwrap().role, Role::PROF);
+    assert!(ACCESS_CTRL.rules(ROLE).contains(&vec!["uarstudent".to_string(), "role:Prof".to_string()]));
+    assert!(!has_rules(POLICY, "uarstudent"));
+  }
+
+  #[test]
+  fn deleted_users_must_leave_nothing_behind() {
+    let admin = add_user("uaxadmin", Role::ADMIN);
+    let prof = add_user("uaxprof", Role::PROF);
+    add_user("uaxassist", Role::PROF);
+    add_user("uaxstudent", Role::STUDENT);
+    let course = Course { published: true, ..Course::new("UAX", "", &["uaxprof"], &["uaxstudent"]) };
+    db::update_users_and_courses(|_, courses| courses.insert(course.id.clone(), course)).unwrap();
+    let grade = Grade { course_id: "UAX".to_string(), ..Grade::legacy(5.0) };
+    assert_eq!(db::add_grade("uaxstudent", &prof, grade), Some(()));
+    let (version, _) = keystore::record_key("uaxstudent").unwrap();
+    let until = Utc::now() + Duration::days(1);
+    db::update_users_and_courses(|_, courses| {
+      let course = courses.get_mut("UAX").unwrap();
+      course.suspensions.push(Suspension { teacher: "uaxprof".to_string(), until });
+      course.delegations.push(Delegation { delegator: "uaxprof".to_string(), delegate: "uaxassist".to_string(), until });
+    }).unwrap();
+
+    assert_eq!(delete_user(&admin, "uaxadmin"), Err(UserAdminError::SelfModification));
+    assert_eq!(delete_user(&admin, "uaxstudent"), Ok(()));
+    assert!(get_user("uaxstudent").is_none());
+    assert_eq!(keystore::record_key_by_id("uaxstudent", version).unwrap(), None);
+    assert_eq!(delete_user(&admin, "uaxprof"), Ok(()));
+    let course = db::get_courses().into_iter().find(|c| c.id == "UAX").unwrap();
+    assert!(course.teachers.is_empty() && course.students.is_empty());
+    assert!(course.suspensions.is_empty() && course.delegations.is_empty());
+    assert_eq!(delete_user(&admin, "uaxprof"), Err(UserAdminError::UnknownUser));
+    // A new account with the same name gets neither the grades nor the keys
+    assert_eq!(create_user(&admin, "uaxstudent", PASSWORD, Role::STUDENT), Ok(()));
+    assert!(keystore::record_key("uaxstudent").unwrap().0 > version);
+  }
+
+  #[test]
+  fn new_password_must_differ_from_the_current_one() {
+    let admin = add_user("uapadmin", Role::ADMIN);
+    assert_eq!(create_user(&admin, "uapstudent", PASSWORD, Role::STUDENT), Ok(()));
+    assert_eq!(change_password("uapstudent", PASSWORD, PASSWORD),
+               Err(UserAdminError::WeakPassword(PasswordPolicyError::SameAsCurrent)));
+    assert_eq!(change_password("uapstudent", "Wrong1Horse", "Other1Horse"), Err(UserAdminError::WrongPassword));
+    assert_eq!(change_password("uapstudent", PASSWORD, "Other1Horse"), Ok(()));
+    assert!(!get_user("uapstudent").unwrap().must_change_pwd);
+  }
+
+  #[test]
+  fn user_names_must_not_match_the_roles() {
+    let admin = add_user("uanadmin", Role::ADMIN);
+    for name in ["Admin", "pROF", "student"] {
+      assert_eq!(create_user(&admin, name, PASSWORD, Role::STUDENT), Err(UserAdminError::ReservedName));
+    }
+    // Even a student named after a role does not get its rules
+    let student = add_user("Admin", Role::STUDENT);
+    let attributes = RequestAttributes::unrestricted(Utc::now());
+    assert!(!ACCESS_CTRL.check_authorization(&student.name, &Resource::USERS.to_string(), &Action::Write.to_string(), &attributes));
+    assert!(!ACCESS_CTRL.check_authorization(&student.name, "courses/SLH", &Action::Write.to_string(), &attributes));
+    assert!(ACCESS_CTRL.check_authorization(&admin.name, &Resource::USERS.to_string(), &Action::Write.to_string(), &attributes));
+  }
+
+  #[test]
+  fn first_admin_must_be_created_once() {
+    // Other tests add administrators, the creation is then refused
+    add_user("uafadmin", Role::ADMIN);
+    assert!(has_admin());
+    assert!(matches!(create_first_admin("uaffirst", "short"), Err(UserAdminError::WeakPassword(_))));
+    assert_eq!(create_first_admin("Admin", PASSWORD), Err(UserAdminError::ReservedName));
+    assert_eq!(create_first_admin("uaffirst", PASSWORD), Err(UserAdminError::Unauthorized));
+    assert!(get_user("uaffirst").is_none());
+  }
+}